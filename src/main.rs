@@ -1,12 +1,70 @@
-use axum::extract::Query;
-use axum::{routing::get, Json, Router};
+use axum::extract::{Path, Query, State};
+use axum::http::{Method, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::{routing::get, routing::post, Json, Router};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use tracing::{info, warn, Level};
+use std::sync::Arc;
+use tracing::{error, info, warn, Level};
 mod ncas_netcdf;
+use ncas_netcdf::cdl::CdlOptions;
+use ncas_netcdf::common::RegexCache;
+use ncas_netcdf::cv_cache::{CacheLimits, CachedCvSource};
+use ncas_netcdf::cv_source::{CvSource, GitHubCvSource, LocalCvSource};
+use ncas_netcdf::error::NcasCdlError;
+use ncas_netcdf::instruments::{InstrumentPage, InstrumentQuery};
+use ncas_netcdf::profiles::{Profile, ProfileStore};
+use ncas_netcdf::rules::AttributeReport;
+use ncas_netcdf::{CdlRequest, OutputFormat};
+use std::time::Duration;
 use tower_http::cors::CorsLayer;
 
+const DEFAULT_TAG: &str = "v2.1.0";
+const DEFAULT_DEPLOYMENT_MODE: &str = "land";
+
+// Shared state handed to every handler
+#[derive(Clone)]
+struct AppState {
+    cv_source: Arc<dyn CvSource>,
+    /// Largest NetCDF file /create-cdl will build.
+    netcdf_max_bytes: u64,
+    /// Regex checks from the common global attributes, compiled once for each tag.
+    regexes: Arc<RegexCache>,
+    profiles: Arc<ProfileStore>,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// Read CV files from local checkouts when CV_SOURCE_DIR is set, otherwise from GitHub,
+// keeping fetched files in a cache shared by all requests
+fn cv_source_from_env() -> Arc<dyn CvSource> {
+    let source: Arc<dyn CvSource> = match std::env::var("CV_SOURCE_DIR") {
+        Ok(dir) if !dir.is_empty() => {
+            info!("Reading controlled vocabularies from {}", dir);
+            Arc::new(LocalCvSource::new(dir))
+        }
+        _ => Arc::new(GitHubCvSource::new()),
+    };
+    let defaults = CacheLimits::default();
+    let limits = CacheLimits {
+        max_entries: env_or("CV_CACHE_MAX_ENTRIES", defaults.max_entries),
+        max_bytes: env_or("CV_CACHE_MAX_BYTES", defaults.max_bytes),
+        mutable_ttl: Duration::from_secs(env_or(
+            "CV_CACHE_MUTABLE_TTL_SECS",
+            defaults.mutable_ttl.as_secs(),
+        )),
+    };
+    info!("Caching controlled vocabularies with limits {:?}", limits);
+    Arc::new(CachedCvSource::new(source, limits))
+}
+
 // Simple handler that returns a String
 async fn status() -> String {
     "Server is up and running!".to_string()
@@ -53,80 +111,438 @@ async fn simple_json(Query(params): Query<HashMap<String, String>>) -> Json<Valu
     Json(data)
 }
 
-async fn get_ncas_netcdf_cdl(Query(params): Query<HashMap<String, String>>) -> Json<Value> {
-    info!("get_ncas_netcdf_cdl called with params: {:?}", params);
-    let tag = params
-        .get("ncas_general_version")
-        .unwrap_or(&"v2.1.0".to_string())
-        .to_string();
-    let deployment = params
-        .get("deployment_mode")
-        .unwrap_or(&"land".to_string())
-        .to_string();
-    let include_requirement_info = params
-        .get("include_requirement_info")
-        .is_some_and(|v| v == "true");
-
-    let instrument_name: String = match params.get("instrument") {
-        Some(instrument) if instrument.is_empty() => {
-            warn!("Instrument parameter is empty");
-            return Json(json!({"error": "Instrument parameter is required"}));
+// Lengths for dimensions the CVs leave to the data, given as e.g. dim_time=1440
+fn dimension_length_params(
+    params: &HashMap<String, String>,
+) -> Result<HashMap<String, u64>, NcasCdlError> {
+    let mut lengths = HashMap::new();
+    for (name, value) in params {
+        if let Some(dimension) = name.strip_prefix("dim_") {
+            match value.parse::<u64>() {
+                Ok(length) if length > 0 => {
+                    lengths.insert(dimension.to_string(), length);
+                }
+                _ => {
+                    return Err(NcasCdlError::InvalidParameter {
+                        name: name.to_string(),
+                        reason: format!("expected a positive integer, got {:?}", value),
+                    });
+                }
+            }
         }
-        Some(instrument) => instrument.to_string(),
-        None => {
-            warn!("Instrument parameter is missing");
-            return Json(json!({"error": "Instrument parameter is required"}));
+    }
+    Ok(lengths)
+}
+
+// A query parameter that must be given and not be empty
+fn required_param(params: &HashMap<String, String>, name: &str) -> Result<String, NcasCdlError> {
+    match params.get(name) {
+        Some(value) if !value.is_empty() => Ok(value.to_string()),
+        _ => {
+            warn!("{} parameter is missing or empty", name);
+            Err(NcasCdlError::MissingParameter(name.to_string()))
         }
+    }
+}
+
+// The date in the file name: YYYY, YYYYMM, YYYYMMDD, or YYYYMMDD-HH, -HHMM or -HHMMSS.
+// It goes into the file name, CDL and headers as it is, so nothing else is accepted.
+fn check_start_date(start_date: &str) -> Result<(), NcasCdlError> {
+    let is_digits = |text: &str, lengths: &[usize]| {
+        lengths.contains(&text.len()) && text.bytes().all(|b| b.is_ascii_digit())
+    };
+    let valid = match start_date.split_once('-') {
+        Some((date, time)) => is_digits(date, &[8]) && is_digits(time, &[2, 4, 6]),
+        None => is_digits(start_date, &[4, 6, 8]),
     };
+    if valid {
+        Ok(())
+    } else {
+        Err(NcasCdlError::InvalidParameter {
+            name: "start_date".to_string(),
+            reason: format!(
+                "expected a date like 20240101 or 20240101-120000, got {:?}",
+                start_date
+            ),
+        })
+    }
+}
 
-    let data_product: String = match params.get("data_product") {
-        Some(data_product) if data_product.is_empty() => {
-            warn!("Data product parameter is empty");
-            return Json(json!({"error": "Data product parameter is required"}));
-        }
-        Some(data_product) => data_product.to_string(),
-        None => {
-            warn!("Data product parameter is missing");
-            return Json(json!({"error": "Data product parameter is required"}));
-        }
+// The AMF_CVs tag to use, given as ncas_general_version
+fn tag_param(params: &HashMap<String, String>) -> String {
+    params
+        .get("ncas_general_version")
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_TAG.to_string())
+}
+
+// The deployment mode to use, given as deployment_mode
+fn deployment_param(params: &HashMap<String, String>) -> String {
+    params
+        .get("deployment_mode")
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_DEPLOYMENT_MODE.to_string())
+}
+
+// A query parameter that is on only when given as true
+fn flag_param(params: &HashMap<String, String>, name: &str) -> bool {
+    params.get(name).is_some_and(|v| v == "true")
+}
+
+// Global attribute values sent as a JSON object of attribute names to values. An empty
+// body gives no values.
+fn attribute_values_from_body(body: &str) -> Result<IndexMap<String, String>, NcasCdlError> {
+    if body.trim().is_empty() {
+        return Ok(IndexMap::new());
+    }
+    serde_json::from_str(body)
+        .map_err(|e| NcasCdlError::InvalidBody(e.to_string()))
+        .inspect_err(|e| warn!("{}", e))
+}
+
+async fn get_ncas_netcdf_cdl(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, NcasCdlError> {
+    info!("get_ncas_netcdf_cdl called with params: {:?}", params);
+    create_cdl(state, params, IndexMap::new()).await
+}
+
+// The same as the GET, with values for global attributes in the body to use instead of
+// the CVs' examples
+async fn post_ncas_netcdf_cdl(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    body: String,
+) -> Result<Response, NcasCdlError> {
+    info!("post_ncas_netcdf_cdl called with params: {:?}", params);
+    let attribute_values = attribute_values_from_body(&body)?;
+    create_cdl(state, params, attribute_values).await
+}
+
+async fn create_cdl(
+    state: AppState,
+    params: HashMap<String, String>,
+    attribute_values: IndexMap<String, String>,
+) -> Result<Response, NcasCdlError> {
+    let tag = tag_param(&params);
+    let deployment = deployment_param(&params);
+    let include_requirement_info = flag_param(&params, "include_requirement_info");
+    let wrap_long_strings = flag_param(&params, "wrap_long_strings");
+    let allow_unregistered_instrument = flag_param(&params, "allow_unregistered_instrument");
+    let strict = flag_param(&params, "strict");
+    let output_format = match params.get("output_format") {
+        Some(output_format) => OutputFormat::from_param(output_format)?,
+        None => OutputFormat::Cdl,
     };
 
-    let start_date: String = match params.get("start_date") {
-        Some(start_date) if start_date.is_empty() => {
-            warn!("Start date parameter is empty");
-            return Json(json!({"error": "Start date parameter is required"}));
-        }
-        Some(start_date) => start_date.to_string(),
-        None => {
-            warn!("Start date parameter is missing");
-            return Json(json!({"error": "Start date parameter is required"}));
+    let instrument_name = required_param(&params, "instrument")?;
+    let data_product = required_param(&params, "data_product")?;
+    let start_date = required_param(&params, "start_date")?;
+    check_start_date(&start_date)?;
+    let profile_values = match params.get("profile") {
+        Some(profile) if !profile.is_empty() => {
+            state
+                .profiles
+                .get(profile)
+                .await
+                .inspect_err(|e| warn!("{}", e))?
+                .attributes
         }
+        _ => IndexMap::new(),
     };
 
-    match ncas_netcdf::main(
+    let request = CdlRequest {
         instrument_name,
         data_product,
         deployment,
         start_date,
         tag,
-        include_requirement_info,
-    )
-    .await
-    {
-        Ok(cdl) => {
+        allow_unregistered_instrument,
+        strict,
+        attribute_values,
+        profile_values,
+    };
+    let cdl = match ncas_netcdf::main(state.cv_source.as_ref(), &state.regexes, request).await {
+        Ok(cdl) => cdl,
+        Err(e) => {
+            warn!("Error fetching NCAS NetCDF data: {}", e);
+            return Err(e);
+        }
+    };
+    match output_format {
+        OutputFormat::Cdl => {
+            let options = CdlOptions {
+                requirement_info: include_requirement_info,
+                wrap_long_strings,
+            };
             let response = json!({
                 "filename": cdl.filename,
-                "cdl": cdl.cdl,
+                "cdl": ncas_netcdf::cdl::make_cdl(&cdl.template, &options),
+                "warnings": cdl.warnings,
             });
-            Json(response)
+            Ok(Json(response).into_response())
         }
-        Err(e) => {
-            warn!("Error fetching NCAS NetCDF data: {}", e);
-            Json(json!({"error": e.to_string()}))
+        OutputFormat::Ncml => {
+            let dimension_lengths = dimension_length_params(&params)?;
+            let ncml = ncas_netcdf::ncml::make_ncml(
+                &cdl.template,
+                &dimension_lengths,
+                include_requirement_info,
+            );
+            let response = json!({
+                "filename": cdl.filename,
+                "ncml": ncml,
+                "warnings": cdl.warnings,
+            });
+            Ok(Json(response).into_response())
+        }
+        OutputFormat::Python => {
+            let response = json!({
+                "filename": cdl.filename,
+                "python": ncas_netcdf::python::make_python_script(
+                    &cdl.template,
+                    include_requirement_info,
+                ),
+                "warnings": cdl.warnings,
+            });
+            Ok(Json(response).into_response())
+        }
+        OutputFormat::Netcdf => {
+            let dimension_lengths = dimension_length_params(&params)?;
+            let netcdf = ncas_netcdf::netcdf_file::make_netcdf(
+                &cdl.template,
+                &dimension_lengths,
+                state.netcdf_max_bytes,
+            )
+            .inspect_err(|e| warn!("Error writing NetCDF file: {}", e))?;
+            let disposition = format!("attachment; filename=\"{}\"", cdl.filename);
+            Ok((
+                [
+                    (header::CONTENT_TYPE, "application/x-netcdf".to_string()),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                netcdf,
+            )
+                .into_response())
+        }
+        OutputFormat::Json => Ok(Json(cdl).into_response()),
+        OutputFormat::Yaml => {
+            let yaml = serde_yaml_ng::to_string(&cdl).map_err(|e| NcasCdlError::OutputFailed {
+                format: "yaml".to_string(),
+                reason: e.to_string(),
+            })?;
+            Ok(([(header::CONTENT_TYPE, "application/yaml")], yaml).into_response())
         }
     }
 }
 
+// Check uploaded CDL against what /create-cdl would generate for the same instrument,
+// data product, deployment mode and tag. The file name is only checked when a start
+// date is given.
+async fn validate_cdl(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    body: String,
+) -> Result<Json<Value>, NcasCdlError> {
+    info!("validate_cdl called with params: {:?}", params);
+    let tag = tag_param(&params);
+    let deployment = deployment_param(&params);
+    let allow_unregistered_instrument = flag_param(&params, "allow_unregistered_instrument");
+    let strict = flag_param(&params, "strict");
+    let instrument_name = required_param(&params, "instrument")?;
+    let data_product = required_param(&params, "data_product")?;
+    let start_date = params.get("start_date").filter(|v| !v.is_empty());
+    if let Some(start_date) = start_date {
+        check_start_date(start_date)?;
+    }
+
+    let parsed = ncas_netcdf::cdl_parser::parse_cdl(&body)
+        .inspect_err(|e| warn!("Error parsing uploaded CDL: {}", e))?;
+    let request = CdlRequest {
+        instrument_name,
+        data_product,
+        deployment,
+        start_date: start_date.map_or("<start date>".to_string(), |v| v.to_string()),
+        tag,
+        allow_unregistered_instrument,
+        strict,
+        attribute_values: IndexMap::new(),
+        profile_values: IndexMap::new(),
+    };
+    let cdl = ncas_netcdf::main(state.cv_source.as_ref(), &state.regexes, request)
+        .await
+        .inspect_err(|e| warn!("Error fetching NCAS NetCDF data: {}", e))?;
+    let report = ncas_netcdf::compliance::check_cdl(&cdl.template, &parsed, start_date.is_some());
+    let mut warnings = cdl.warnings;
+    warnings.extend(parsed.warnings);
+    Ok(Json(json!({
+        "compliant": report.compliant,
+        "problems": report.problems,
+        "warnings": warnings,
+    })))
+}
+
+// Check the values a user intends to give global attributes, sent as a JSON object of
+// attribute names to values
+async fn check_global_attributes(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    body: String,
+) -> Result<Json<AttributeReport>, NcasCdlError> {
+    info!("check_global_attributes called with params: {:?}", params);
+    let tag = tag_param(&params);
+    let data_product = required_param(&params, "data_product")?;
+    let values = attribute_values_from_body(&body)?;
+    let report = ncas_netcdf::check_global_attributes(
+        state.cv_source.as_ref(),
+        &state.regexes,
+        data_product,
+        tag,
+        &values,
+    )
+    .await
+    .inspect_err(|e| warn!("Error checking global attributes: {}", e))?;
+    Ok(Json(report))
+}
+
+async fn get_data_products(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, NcasCdlError> {
+    info!("get_data_products called with params: {:?}", params);
+    let tag = tag_param(&params);
+    let data_products =
+        ncas_netcdf::data_products::list_data_products(state.cv_source.as_ref(), tag.clone())
+            .await
+            .inspect_err(|e| warn!("Error listing data products: {}", e))?;
+    Ok(Json(json!({
+        "ncas_general_version": tag,
+        "data_products": data_products,
+    })))
+}
+
+async fn get_deployment_modes(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, NcasCdlError> {
+    info!("get_deployment_modes called with params: {:?}", params);
+    let tag = tag_param(&params);
+    let deployment_modes =
+        ncas_netcdf::deployments::list_deployment_modes(state.cv_source.as_ref(), tag.clone())
+            .await
+            .inspect_err(|e| warn!("Error listing deployment modes: {}", e))?;
+    Ok(Json(json!({
+        "ncas_general_version": tag,
+        "deployment_modes": deployment_modes,
+    })))
+}
+
+// Parse an optional positive integer query parameter
+fn positive_param(
+    params: &HashMap<String, String>,
+    name: &str,
+    default: usize,
+) -> Result<usize, NcasCdlError> {
+    match params.get(name) {
+        None => Ok(default),
+        Some(value) => match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(NcasCdlError::InvalidParameter {
+                name: name.to_string(),
+                reason: format!("expected a positive integer, got {:?}", value),
+            }),
+        },
+    }
+}
+
+async fn get_instruments(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<InstrumentPage>, NcasCdlError> {
+    info!("get_instruments called with params: {:?}", params);
+    let non_empty = |name: &str| params.get(name).filter(|v| !v.is_empty()).cloned();
+    let query = InstrumentQuery {
+        q: non_empty("q"),
+        category: non_empty("category"),
+        owner: non_empty("owner"),
+        page: positive_param(&params, "page", 1)?,
+        per_page: positive_param(&params, "per_page", 20)?.min(100),
+    };
+    let page = ncas_netcdf::instruments::search_instruments(state.cv_source.as_ref(), query)
+        .await
+        .inspect_err(|e| warn!("Error searching instruments: {}", e))?;
+    Ok(Json(page))
+}
+
+async fn get_instrument_data_products(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, NcasCdlError> {
+    info!("get_instrument_data_products called for {}", name);
+    let (record, warnings) =
+        ncas_netcdf::instruments::get_instrument_data(state.cv_source.as_ref(), name, false)
+            .await
+            .inspect_err(|e| warn!("Error fetching instrument data products: {}", e))?;
+    Ok(Json(json!({
+        "instrument": record.instrument_name,
+        "data_products": record.data_products(),
+        "warnings": warnings,
+    })))
+}
+
+async fn list_profiles(State(state): State<AppState>) -> Json<Vec<Profile>> {
+    info!("list_profiles called");
+    Json(state.profiles.list().await)
+}
+
+async fn get_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Profile>, NcasCdlError> {
+    info!("get_profile called for {}", name);
+    let profile = state
+        .profiles
+        .get(&name)
+        .await
+        .inspect_err(|e| warn!("{}", e))?;
+    Ok(Json(profile))
+}
+
+// Create or replace a profile from a JSON object of global attribute names to values
+async fn put_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    body: String,
+) -> Result<(StatusCode, Json<Profile>), NcasCdlError> {
+    info!("put_profile called for {}", name);
+    let attributes = attribute_values_from_body(&body)?;
+    let created = state
+        .profiles
+        .put(&name, attributes.clone())
+        .await
+        .inspect_err(|e| warn!("Error saving profile {}: {}", name, e))?;
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(Profile { name, attributes })))
+}
+
+async fn delete_profile(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, NcasCdlError> {
+    info!("delete_profile called for {}", name);
+    state
+        .profiles
+        .delete(&name)
+        .await
+        .inspect_err(|e| warn!("Error deleting profile {}: {}", name, e))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[tokio::main]
 async fn main() {
     // Initialize the logger
@@ -135,7 +551,27 @@ async fn main() {
         "http://localhost:5173".parse().unwrap(),
         "http://localhost".parse().unwrap(),
     ];
-    let cors = CorsLayer::new().allow_origin(origins);
+    // Requests with a body set Content-Type, which preflight requests have to allow.
+    // Profiles are saved with PUT and removed with DELETE.
+    let cors = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::CONTENT_TYPE]);
+    let profiles_file: String = env_or("PROFILES_FILE", "profiles.json".to_string());
+    info!("Saving profiles in {}", profiles_file);
+    let profiles = match ProfileStore::open(profiles_file).await {
+        Ok(profiles) => profiles,
+        Err(e) => {
+            error!("Could not load saved profiles: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let state = AppState {
+        cv_source: cv_source_from_env(),
+        netcdf_max_bytes: env_or("NETCDF_MAX_BYTES", 100 * 1024 * 1024),
+        regexes: Arc::new(RegexCache::default()),
+        profiles: Arc::new(profiles),
+    };
 
     let root_addr = "/ncas-general-cdl/api";
     // Define the routes
@@ -145,9 +581,33 @@ async fn main() {
         .route(&format!("{}/simple_json", root_addr), get(simple_json))
         .route(
             &format!("{}/create-cdl", root_addr),
-            get(get_ncas_netcdf_cdl),
+            get(get_ncas_netcdf_cdl).post(post_ncas_netcdf_cdl),
+        )
+        .route(&format!("{}/validate-cdl", root_addr), post(validate_cdl))
+        .route(
+            &format!("{}/check-global-attributes", root_addr),
+            post(check_global_attributes),
+        )
+        .route(&format!("{}/profiles", root_addr), get(list_profiles))
+        .route(
+            &format!("{}/profiles/{{name}}", root_addr),
+            get(get_profile).put(put_profile).delete(delete_profile),
+        )
+        .route(
+            &format!("{}/data-products", root_addr),
+            get(get_data_products),
+        )
+        .route(
+            &format!("{}/deployment-modes", root_addr),
+            get(get_deployment_modes),
+        )
+        .route(&format!("{}/instruments", root_addr), get(get_instruments))
+        .route(
+            &format!("{}/instruments/{{name}}/data-products", root_addr),
+            get(get_instrument_data_products),
         )
-        .layer(cors);
+        .layer(cors)
+        .with_state(state);
 
     // Start the server
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
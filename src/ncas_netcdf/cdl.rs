@@ -1,268 +1,314 @@
-use super::common::Common;
-use super::data_products::DataProduct;
-use super::deployments::Deployment;
-use super::instruments::InstRecord;
-use super::netcdf_components::{GlobalAttribute, Variable};
-use std::error::Error;
+use super::template::{
+    AttributeValue, DataType, Number, RequirementLevel, Template, TemplateAttribute,
+    TemplateDimension, TemplateVariable,
+};
 
-#[derive(Debug)]
-pub enum RequirementLevel {
-    Required,
-    RequiredIf(String),
-    Optional,
+/// How the CDL should be laid out.
+#[derive(Debug, Default)]
+pub struct CdlOptions {
+    /// Add a comment to each line saying whether it is required.
+    pub requirement_info: bool,
+    /// Split long string values over several lines.
+    pub wrap_long_strings: bool,
 }
 
 const MAX_LINE_LENGTH: usize = 100;
 
 fn requirement_level_string(level: &RequirementLevel, spaces: usize) -> String {
-    let spaces_str = " ".repeat(spaces);
-    match level {
-        RequirementLevel::Required => format!("{}// Required", spaces_str),
-        RequirementLevel::RequiredIf(reason) => format!("{}// Required if {}", spaces_str, reason),
-        RequirementLevel::Optional => format!("{}// Optional", spaces_str),
+    format!("{}// {}", " ".repeat(spaces), level)
+}
+
+// Spaces needed after the last line of `text` to line a requirement comment up at
+// MAX_LINE_LENGTH
+fn requirement_spaces(text: &str) -> usize {
+    let last_line_length = text.rsplit('\n').next().unwrap_or_default().chars().count();
+    if last_line_length > MAX_LINE_LENGTH {
+        2
+    } else {
+        MAX_LINE_LENGTH - last_line_length
     }
 }
 
-fn map_data_type(data_type: &str) -> String {
-    match data_type {
-        "int8" => "byte".to_string(),
-        "byte" => "byte".to_string(),
-        "int32" => "int".to_string(),
-        "float32" => "float".to_string(),
-        "float64" => "double".to_string(),
-        "string" => "string".to_string(),
-        _ => "unknown".to_string(),
+// Escape a value for use inside a CDL string constant. Non-ASCII characters are left as
+// they are, since ncgen reads CDL as UTF-8.
+fn escape_cdl_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
     }
+    escaped
 }
 
-fn create_dimension_text(
-    dimension_name: &str,
-    dimension_length: Option<u32>,
-    requirement_info: bool,
-) -> String {
-    let dim_length = match dimension_length {
-        Some(length) => length.to_string(),
-        None => "<dim length>".to_string(),
-    };
-    let dimension_text = format!("        {} = {} ;", dimension_name, dim_length);
-    let len_dimension_text = dimension_text.len();
-    let spaces = if len_dimension_text > MAX_LINE_LENGTH {
-        2
-    } else {
-        MAX_LINE_LENGTH - len_dimension_text
-    };
-    let requirement_text = if requirement_info {
-        requirement_level_string(&RequirementLevel::Required, spaces)
-    } else {
-        "".to_string()
-    };
-    format!("{}{}\n", dimension_text, requirement_text)
+fn cdl_string(value: &str) -> String {
+    format!("\"{}\"", escape_cdl_string(value))
 }
 
-fn dimension_section(
-    deployment: &Deployment,
-    data_product: &DataProduct,
-    requirement_info: bool,
-) -> String {
-    let mut section = String::new();
-    section.push_str("dimensions:\n");
-    for dimension in &deployment.dimensions {
-        section.push_str(&create_dimension_text(
-            &dimension.name,
-            dimension.length,
-            requirement_info,
-        ));
+// Split a value into escaped pieces of at most `width` characters, breaking after spaces
+// and newlines where possible
+fn split_cdl_string(value: &str, width: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut piece_length = 0;
+    for word in value.split_inclusive([' ', '\n']) {
+        let escaped_word = escape_cdl_string(word);
+        let word_length = escaped_word.chars().count();
+        if piece_length + word_length > width && !piece.is_empty() {
+            pieces.push(std::mem::take(&mut piece));
+            piece_length = 0;
+        }
+        if word_length <= width {
+            piece.push_str(&escaped_word);
+            piece_length += word_length;
+            continue;
+        }
+        // A word longer than a whole line has to be broken between characters
+        for c in word.chars() {
+            let escaped_char = escape_cdl_string(&c.to_string());
+            let char_length = escaped_char.chars().count();
+            if piece_length + char_length > width && !piece.is_empty() {
+                pieces.push(std::mem::take(&mut piece));
+                piece_length = 0;
+            }
+            piece.push_str(&escaped_char);
+            piece_length += char_length;
+        }
     }
-    for dimension in &data_product.dimensions {
-        section.push_str(&create_dimension_text(
-            &dimension.name,
-            dimension.length,
-            requirement_info,
-        ));
+    if !piece.is_empty() || pieces.is_empty() {
+        pieces.push(piece);
     }
-    section
+    pieces
 }
 
-fn create_variable_text(
-    variable: &Variable,
-    requirement_info: bool,
-    requirement_level_variable: RequirementLevel,
-    requirement_level_varattr: RequirementLevel,
-) -> String {
-    let default_type = &"unknown".to_string();
-    let vartype = variable.attributes.get("type").unwrap_or(default_type);
-    let vartype = &map_data_type(vartype);
-    let vardims = variable
-        .attributes
-        .get("dimension")
-        .map_or_else(|| "".to_string(), |dims| dims.to_string());
-    let mut variable_text = format!("        {} {}({}) ;", vartype, variable.name, vardims);
-    let len_variable_text = variable_text.len();
-    let spaces = if len_variable_text > MAX_LINE_LENGTH {
-        2
-    } else {
-        MAX_LINE_LENGTH - len_variable_text
-    };
-    let requirement_text = if requirement_info {
-        requirement_level_string(&requirement_level_variable, spaces)
+// Write `<lhs> = "<value>" ;`. With `wrap`, a value that would take the line past
+// MAX_LINE_LENGTH is split into several string constants, one per line, which ncgen
+// concatenates back together.
+fn string_attribute_text(lhs: &str, value: &str, wrap: bool) -> String {
+    let single_line = format!("{} = {} ;", lhs, cdl_string(value));
+    if !wrap || single_line.chars().count() <= MAX_LINE_LENGTH {
+        return single_line;
+    }
+    let indent = lhs.chars().count() + 3;
+    // Leave room for the quotes and the trailing comma or " ;"
+    let width = MAX_LINE_LENGTH.saturating_sub(indent + 4).max(20);
+    let pieces: Vec<String> = split_cdl_string(value, width)
+        .iter()
+        .map(|piece| format!("\"{}\"", piece))
+        .collect();
+    format!(
+        "{} = {} ;",
+        lhs,
+        pieces.join(&format!(",\n{}", " ".repeat(indent)))
+    )
+}
+
+// Append the requirement comment to the last line of a statement, if asked for
+fn with_requirement(text: String, requirement: &RequirementLevel, options: &CdlOptions) -> String {
+    let requirement_text = if options.requirement_info {
+        requirement_level_string(requirement, requirement_spaces(&text))
     } else {
         "".to_string()
     };
-    variable_text.push_str(&requirement_text);
-    variable_text.push('\n');
-    for (attr_name, attr_value) in &variable.attributes {
-        if attr_name == "type" || attr_name == "dimension" {
-            continue; // Skip type and dimension attributes
-        }
-        let attr_text = if attr_name == "_FillValue" {
-            format!(
-                "                {}:{} = -1.e+20f ;",
-                variable.name, attr_name
-            )
-        } else {
-            format!(
-                "                {}:{} = \"{}\" ;",
-                variable.name, attr_name, attr_value
-            )
-        };
-        let len_attr_text = attr_text.len();
-        let spaces = if len_attr_text > MAX_LINE_LENGTH {
-            2
-        } else {
-            MAX_LINE_LENGTH - len_attr_text
-        };
-        let requirement_text = if requirement_info {
-            requirement_level_string(&requirement_level_varattr, spaces)
-        } else {
-            "".to_string()
-        };
-        variable_text.push_str(&format!("{}{}\n", attr_text, requirement_text));
-    }
-    variable_text
+    format!("{}{}\n", text, requirement_text)
 }
 
-fn variable_section(
-    deployment: &Deployment,
-    data_product: &DataProduct,
-    requirement_info: bool,
-) -> String {
-    let mut section = String::new();
-    section.push_str("variables:\n");
-    for variable in &deployment.variables {
-        section.push_str(&create_variable_text(
-            variable,
-            requirement_info,
-            RequirementLevel::Required,
-            RequirementLevel::Required,
-        ));
-    }
-    for variable in &data_product.variables {
-        section.push_str(&create_variable_text(
-            variable,
-            requirement_info,
-            RequirementLevel::Optional,
-            RequirementLevel::RequiredIf("the variable is present".to_string()),
-        ));
+// Render a number as a CDL constant of the given type, e.g. 2 as a byte is "2b"
+fn cdl_number(number: &Number, data_type: DataType) -> String {
+    let suffix = match data_type {
+        DataType::Byte => "b",
+        DataType::Ubyte => "ub",
+        DataType::Short => "s",
+        DataType::Ushort => "us",
+        DataType::Uint => "u",
+        DataType::Int64 => "ll",
+        DataType::Uint64 => "ull",
+        _ => "",
+    };
+    match number {
+        Number::Signed(v) => format!("{}{}", v, suffix),
+        Number::Unsigned(v) => format!("{}{}", v, suffix),
+        Number::Float(v) if data_type == DataType::Float => format!("{:?}f", *v as f32),
+        Number::Float(v) => format!("{:?}", v),
     }
-    section
 }
 
-fn create_attribute_text(
-    attr: &GlobalAttribute,
-    instrument_record: &InstRecord,
-    requirement_info: bool,
-) -> String {
-    let value = if attr.name == "instrument_manufacturer" {
-        instrument_record.manufacturer.clone()
-    } else if attr.name == "instrument_model" {
-        instrument_record.model_no.clone()
-    } else if attr.name == "instrument_serial_number" {
-        instrument_record.serial_number.clone()
-    } else if attr.name == "source" {
-        instrument_record.descriptor.clone()
-    } else {
-        Some(if attr.value.is_empty() {
-            format!("EXAMPLE: {}", attr.example.clone())
-        } else {
-            attr.value.clone()
-        })
-    };
-    let value = match value {
-        Some(v) => v,
-        None => "EXAMPLE".to_string(),
-    };
-    let attr_text = format!("                {} = \"{}\" ;", attr.name, value);
-    let len_attr_text = attr_text.len();
-    let spaces = if len_attr_text > MAX_LINE_LENGTH {
-        2
-    } else {
-        MAX_LINE_LENGTH - len_attr_text
+fn create_attribute_text(lhs: &str, attr: &TemplateAttribute, options: &CdlOptions) -> String {
+    let attr_text = match &attr.value {
+        AttributeValue::Numbers(numbers) => {
+            let values: Vec<String> = numbers
+                .iter()
+                .map(|number| cdl_number(number, attr.data_type))
+                .collect();
+            format!("{} = {} ;", lhs, values.join(", "))
+        }
+        AttributeValue::Text(value) => string_attribute_text(lhs, value, options.wrap_long_strings),
     };
-    let requirement_text = if requirement_info {
-        requirement_level_string(&RequirementLevel::Required, spaces)
-    } else {
-        "".to_string()
+    with_requirement(attr_text, &attr.requirement, options)
+}
+
+fn create_dimension_text(dimension: &TemplateDimension, options: &CdlOptions) -> String {
+    let dim_length = match dimension.length {
+        Some(length) => length.to_string(),
+        None => "<dim length>".to_string(),
     };
-    format!("{}{}\n", attr_text, requirement_text)
+    let dimension_text = format!("        {} = {} ;", dimension.name, dim_length);
+    with_requirement(dimension_text, &dimension.requirement, options)
 }
 
-fn attribute_section(
-    common: &Common,
-    data_product: &DataProduct,
-    instrument_record: &InstRecord,
-    requirement_info: bool,
-) -> String {
-    let mut section = String::new();
-    section.push_str("\n// global attributes:\n");
-    for attr in &common.global_attributes {
-        section.push_str(&create_attribute_text(
-            attr,
-            instrument_record,
-            requirement_info,
-        ));
-    }
-    for attr in &data_product.global_attributes {
-        section.push_str(&create_attribute_text(
-            attr,
-            instrument_record,
-            requirement_info,
-        ));
+fn create_variable_text(variable: &TemplateVariable, options: &CdlOptions) -> String {
+    let declaration = if variable.dimensions.is_empty() {
+        format!("        {} {} ;", variable.data_type.name(), variable.name)
+    } else {
+        format!(
+            "        {} {}({}) ;",
+            variable.data_type.name(),
+            variable.name,
+            variable.dimensions.join(", ")
+        )
+    };
+    let mut variable_text = with_requirement(declaration, &variable.requirement, options);
+    for attr in &variable.attributes {
+        let lhs = format!("                {}:{}", variable.name, attr.name);
+        variable_text.push_str(&create_attribute_text(&lhs, attr, options));
     }
-    section
+    variable_text
 }
 
-pub fn make_cdl(
-    file_name: String,
-    common: Common,
-    deployment: Deployment,
-    data_product: DataProduct,
-    instrument_record: InstRecord,
-    requirement_info: bool,
-) -> Result<String, Box<dyn Error>> {
+pub fn make_cdl(template: &Template, options: &CdlOptions) -> String {
     let mut cdl = String::new();
     cdl.push_str(
         format!(
             "netcdf {} {{\n",
-            file_name.strip_suffix(".nc").unwrap_or(&file_name)
+            template
+                .filename
+                .strip_suffix(".nc")
+                .unwrap_or(&template.filename)
         )
         .as_str(),
     );
-    cdl.push_str(&dimension_section(
-        &deployment,
-        &data_product,
-        requirement_info,
-    ));
-    cdl.push_str(&variable_section(
-        &deployment,
-        &data_product,
-        requirement_info,
-    ));
-    cdl.push_str(&attribute_section(
-        &common,
-        &data_product,
-        &instrument_record,
-        requirement_info,
-    ));
+    cdl.push_str("dimensions:\n");
+    for dimension in &template.dimensions {
+        cdl.push_str(&create_dimension_text(dimension, options));
+    }
+    cdl.push_str("variables:\n");
+    for variable in &template.variables {
+        cdl.push_str(&create_variable_text(variable, options));
+    }
+    cdl.push_str("\n// global attributes:\n");
+    for attr in &template.global_attributes {
+        let lhs = format!("                :{}", attr.name);
+        cdl.push_str(&create_attribute_text(&lhs, attr, options));
+    }
     cdl.push('}');
-    Ok(cdl)
+    cdl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(
+            escape_cdl_string("a \"b\" c:\\d\nline\r\t\x07\x1b"),
+            "a \\\"b\\\" c:\\\\d\\nline\\r\\t\\x07\\x1b"
+        );
+        // ncgen reads CDL as UTF-8, so these are written as they are
+        assert_eq!(escape_cdl_string("été 20 °C Δt"), "été 20 °C Δt");
+    }
+
+    #[test]
+    fn long_strings_are_split_after_spaces() {
+        assert_eq!(
+            split_cdl_string("the quick brown fox", 10),
+            ["the quick ", "brown fox"]
+        );
+        assert_eq!(
+            split_cdl_string("line one\nline two", 10),
+            ["line one\\n", "line two"]
+        );
+        assert_eq!(split_cdl_string("", 10), [""]);
+    }
+
+    #[test]
+    fn words_longer_than_a_line_are_broken() {
+        assert_eq!(
+            split_cdl_string("a https://example.com/long/path", 10),
+            ["a ", "https://ex", "ample.com/", "long/path"]
+        );
+        // Escapes are kept whole and count towards the width
+        assert_eq!(split_cdl_string("abcdefghi\"", 10), ["abcdefghi", "\\\""]);
+        assert_eq!(split_cdl_string("ééééééééééé", 10), ["éééééééééé", "é"]);
+    }
+
+    #[test]
+    fn wrapped_attributes_are_aligned() {
+        let lhs = "        :title";
+        let value = "word ".repeat(30);
+        assert_eq!(
+            string_attribute_text(lhs, &value, false),
+            format!("{} = \"{}\" ;", lhs, value)
+        );
+        let text = string_attribute_text(lhs, &value, true);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.len() > 1);
+        for line in &lines {
+            assert!(line.chars().count() <= MAX_LINE_LENGTH, "{}", line);
+        }
+        for line in &lines[1..] {
+            assert!(line.starts_with(&format!("{}\"", " ".repeat(lhs.len() + 3))));
+        }
+        assert!(lines.last().unwrap().ends_with("\" ;"));
+        // Short values stay on one line
+        assert_eq!(
+            string_attribute_text(lhs, "short", true),
+            format!("{} = \"short\" ;", lhs)
+        );
+    }
+
+    #[test]
+    fn requirement_comments_follow_the_last_line() {
+        let options = CdlOptions {
+            requirement_info: true,
+            wrap_long_strings: true,
+        };
+        let text = string_attribute_text("        :title", &"word ".repeat(30), true);
+        let commented = with_requirement(text.clone(), &RequirementLevel::Required, &options);
+        let last_line = commented.lines().last().unwrap();
+        assert_eq!(last_line.find("// Required"), Some(MAX_LINE_LENGTH));
+        assert!(commented.starts_with(&text));
+        // Lines already past the limit get the comment two spaces after them
+        let long_line = "x".repeat(MAX_LINE_LENGTH + 5);
+        assert_eq!(
+            with_requirement(long_line.clone(), &RequirementLevel::Optional, &options),
+            format!("{}  // Optional\n", long_line)
+        );
+    }
+
+    #[test]
+    fn numbers_have_the_suffix_for_their_type() {
+        let cases = [
+            (Number::Signed(-2), DataType::Byte, "-2b"),
+            (Number::Unsigned(2), DataType::Ubyte, "2ub"),
+            (Number::Signed(-2), DataType::Short, "-2s"),
+            (Number::Unsigned(2), DataType::Ushort, "2us"),
+            (Number::Signed(-2), DataType::Int, "-2"),
+            (Number::Unsigned(2), DataType::Uint, "2u"),
+            (Number::Signed(-2), DataType::Int64, "-2ll"),
+            (Number::Unsigned(2), DataType::Uint64, "2ull"),
+            (Number::Float(2.0), DataType::Float, "2.0f"),
+            (Number::Float(-1.0e20), DataType::Float, "-1e20f"),
+            (Number::Float(0.5), DataType::Double, "0.5"),
+        ];
+        for (number, data_type, expected) in cases {
+            assert_eq!(cdl_number(&number, data_type), expected, "{:?}", data_type);
+        }
+    }
 }
@@ -0,0 +1,561 @@
+use super::error::NcasCdlError;
+use super::template::{AttributeValue, DataType, Number};
+
+/// The header of a NetCDF file as written in CDL, e.g. by `ncdump -h`. Anything after
+/// `data:` is ignored.
+#[derive(Debug, Default)]
+pub struct ParsedCdl {
+    pub name: String,
+    pub dimensions: Vec<ParsedDimension>,
+    pub variables: Vec<ParsedVariable>,
+    pub global_attributes: Vec<ParsedAttribute>,
+    /// Things that were read leniently, such as placeholders left in from a template.
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct ParsedDimension {
+    pub name: String,
+    /// None for an unlimited dimension.
+    pub length: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct ParsedVariable {
+    pub name: String,
+    pub data_type: DataType,
+    pub dimensions: Vec<String>,
+    pub attributes: Vec<ParsedAttribute>,
+}
+
+#[derive(Debug)]
+pub struct ParsedAttribute {
+    pub name: String,
+    pub data_type: DataType,
+    pub value: AttributeValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Text(String),
+    Number(Number, DataType),
+    Symbol(char),
+    /// Something like `<dim length>`, which templates use for what the data decides.
+    Placeholder(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Text(text) => write!(f, "{:?}", text),
+            Token::Number(number, _) => write!(f, "{}", number),
+            Token::Symbol(c) => write!(f, "'{}'", c),
+            Token::Placeholder(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+fn invalid(line: usize, reason: impl Into<String>) -> NcasCdlError {
+    NcasCdlError::InvalidCdl {
+        line,
+        reason: reason.into(),
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | '+' | '@')
+}
+
+// Read a numeric constant, using its suffix (or lack of one) for its type as ncgen does
+fn parse_number(text: &str, line: usize) -> Result<(Number, DataType), NcasCdlError> {
+    let lower = text.to_ascii_lowercase();
+    let bad_number = || invalid(line, format!("{} is not a number", text));
+    let is_float = lower.contains(['.', 'e']) || lower.contains("inf") || lower.contains("nan");
+    if is_float && !lower.starts_with("0x") {
+        let (digits, data_type) = match lower.strip_suffix('f') {
+            // "inf" ends in an f that isn't a suffix
+            Some(digits) if !lower.ends_with("inf") => (digits, DataType::Float),
+            _ => (lower.trim_end_matches('d'), DataType::Double),
+        };
+        let value = match digits.trim_start_matches('+') {
+            "nan" | "-nan" => f64::NAN,
+            "inf" | "infinity" => f64::INFINITY,
+            "-inf" | "-infinity" => f64::NEG_INFINITY,
+            digits => digits.parse::<f64>().map_err(|_| bad_number())?,
+        };
+        return Ok((Number::Float(value), data_type));
+    }
+    let suffixes = [
+        ("ull", DataType::Uint64),
+        ("ll", DataType::Int64),
+        ("ub", DataType::Ubyte),
+        ("us", DataType::Ushort),
+        ("u", DataType::Uint),
+        ("b", DataType::Byte),
+        ("s", DataType::Short),
+        ("l", DataType::Int),
+    ];
+    let (digits, data_type) = suffixes
+        .iter()
+        .find_map(|(suffix, data_type)| {
+            lower
+                .strip_suffix(suffix)
+                .map(|digits| (digits, *data_type))
+        })
+        .unwrap_or((&lower, DataType::Int));
+    let number = match digits.parse::<i64>() {
+        Ok(value) => Number::Signed(value),
+        Err(_) => Number::Unsigned(digits.parse::<u64>().map_err(|_| bad_number())?),
+    };
+    Ok((number, data_type))
+}
+
+fn push_char(text: &mut Vec<u8>, c: char) {
+    text.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+// Split CDL into tokens, each with the line it starts on
+fn tokenize(cdl: &str) -> Result<Vec<(Token, usize)>, NcasCdlError> {
+    let mut tokens = Vec::new();
+    let mut chars = cdl.chars().peekable();
+    let mut line = 1;
+    while let Some(&c) = chars.peek() {
+        if c == '\n' {
+            line += 1;
+            chars.next();
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c == '/' {
+            chars.next();
+            if chars.peek() != Some(&'/') {
+                return Err(invalid(line, "unexpected /"));
+            }
+            // Comments run to the end of the line
+            while chars.peek().is_some_and(|&c| c != '\n') {
+                chars.next();
+            }
+        } else if c == '"' {
+            chars.next();
+            let start_line = line;
+            // \x escapes are single bytes of UTF-8, so the text is built up as bytes
+            let mut text = Vec::new();
+            loop {
+                match chars.next() {
+                    None => return Err(invalid(start_line, "unterminated string")),
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => push_char(&mut text, '\n'),
+                        Some('t') => push_char(&mut text, '\t'),
+                        Some('r') => push_char(&mut text, '\r'),
+                        Some('0') => push_char(&mut text, '\0'),
+                        Some('x') => {
+                            let hex: String = (0..2).filter_map(|_| chars.next()).collect();
+                            let byte = u8::from_str_radix(&hex, 16)
+                                .map_err(|_| invalid(line, format!("bad escape \\x{}", hex)))?;
+                            text.push(byte);
+                        }
+                        Some(escaped) => push_char(&mut text, escaped),
+                        None => return Err(invalid(start_line, "unterminated string")),
+                    },
+                    Some(c) => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        push_char(&mut text, c);
+                    }
+                }
+            }
+            let text = String::from_utf8(text)
+                .map_err(|_| invalid(start_line, "string is not valid UTF-8"))?;
+            tokens.push((Token::Text(text), start_line));
+        } else if c == '<' {
+            let mut text = String::new();
+            while let Some(c) = chars.next_if(|&c| c != '\n') {
+                text.push(c);
+                if c == '>' {
+                    break;
+                }
+            }
+            if !text.ends_with('>') {
+                return Err(invalid(line, format!("unexpected {}", text)));
+            }
+            tokens.push((Token::Placeholder(text), line));
+        } else if c.is_ascii_digit() || matches!(c, '-' | '+' | '.') {
+            let mut text = String::new();
+            while let Some(&c) = chars.peek() {
+                // Signs only appear at the start and after an exponent
+                let sign_allowed = text.is_empty() || text.ends_with(['e', 'E']);
+                if c.is_ascii_alphanumeric() || c == '.' || (matches!(c, '-' | '+') && sign_allowed)
+                {
+                    text.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let (number, data_type) = parse_number(&text, line)?;
+            tokens.push((Token::Number(number, data_type), line));
+        } else if is_name_start(c) {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if is_name_char(c) {
+                    name.push(c);
+                    chars.next();
+                } else if c == '\\' {
+                    // Escaped characters in names, e.g. "a\ b"
+                    chars.next();
+                    if let Some(escaped) = chars.next() {
+                        name.push(escaped);
+                    }
+                } else {
+                    break;
+                }
+            }
+            // NaN and Infinity can be written like names
+            let lower = name.to_ascii_lowercase();
+            if matches!(
+                lower.as_str(),
+                "nan" | "nanf" | "infinity" | "infinityf" | "inf" | "inff"
+            ) {
+                let (number, data_type) = parse_number(&lower, line)?;
+                tokens.push((Token::Number(number, data_type), line));
+            } else {
+                tokens.push((Token::Name(name), line));
+            }
+        } else if "{}()=;,:".contains(c) {
+            tokens.push((Token::Symbol(c), line));
+            chars.next();
+        } else {
+            return Err(invalid(line, format!("unexpected character {:?}", c)));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens
+            .get(self.position + offset)
+            .map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .or(self.tokens.last())
+            .map_or(1, |(_, line)| *line)
+    }
+
+    fn next(&mut self) -> Result<Token, NcasCdlError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .map(|(token, _)| token.clone())
+            .ok_or_else(|| invalid(self.line(), "unexpected end of CDL"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), NcasCdlError> {
+        let line = self.line();
+        match self.next()? {
+            Token::Symbol(c) if c == symbol => Ok(()),
+            token => Err(invalid(
+                line,
+                format!("expected {} but found {}", symbol, token),
+            )),
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, NcasCdlError> {
+        let line = self.line();
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            token => Err(invalid(
+                line,
+                format!("expected a name but found {}", token),
+            )),
+        }
+    }
+
+    fn at_symbol(&self, symbol: char) -> bool {
+        self.peek() == Some(&Token::Symbol(symbol))
+    }
+
+    // Whether the next tokens start a section, e.g. "variables:"
+    fn at_section(&self) -> bool {
+        matches!(
+            (self.peek(), self.peek_at(1)),
+            (Some(Token::Name(name)), Some(Token::Symbol(':')))
+                if matches!(name.as_str(), "dimensions" | "variables" | "data" | "types" | "group")
+        ) || self.at_symbol('}')
+    }
+
+    fn dimensions(&mut self, cdl: &mut ParsedCdl) -> Result<(), NcasCdlError> {
+        while !self.at_section() {
+            let name = self.expect_name()?;
+            self.expect_symbol('=')?;
+            let line = self.line();
+            let length = match self.next()? {
+                Token::Name(unlimited) if unlimited.eq_ignore_ascii_case("unlimited") => None,
+                Token::Number(Number::Signed(length), _) if length >= 0 => Some(length as u64),
+                Token::Number(Number::Unsigned(length), _) => Some(length),
+                // CDL from /create-cdl leaves lengths that depend on the data to be filled in
+                Token::Placeholder(placeholder) => {
+                    cdl.warnings.push(format!(
+                        "Line {}: {} has the length {}, so was read as UNLIMITED",
+                        line, name, placeholder
+                    ));
+                    None
+                }
+                token => {
+                    return Err(invalid(
+                        line,
+                        format!("expected the length of {} but found {}", name, token),
+                    ));
+                }
+            };
+            cdl.dimensions.push(ParsedDimension { name, length });
+            if !self.at_symbol(',') {
+                self.expect_symbol(';')?;
+            } else {
+                self.next()?;
+            }
+        }
+        Ok(())
+    }
+
+    // The values of an attribute, up to and including the closing semicolon
+    fn attribute_value(
+        &mut self,
+        declared_type: Option<DataType>,
+    ) -> Result<(DataType, AttributeValue), NcasCdlError> {
+        let line = self.line();
+        let mut texts = Vec::new();
+        let mut numbers = Vec::new();
+        let mut number_type = None;
+        loop {
+            match self.next()? {
+                Token::Text(text) => texts.push(text),
+                Token::Number(number, data_type) => {
+                    numbers.push(number);
+                    number_type.get_or_insert(data_type);
+                }
+                token => {
+                    return Err(invalid(
+                        line,
+                        format!("expected an attribute value but found {}", token),
+                    ));
+                }
+            }
+            if self.at_symbol(',') {
+                self.next()?;
+            } else {
+                self.expect_symbol(';')?;
+                break;
+            }
+        }
+        match (texts.is_empty(), numbers.is_empty()) {
+            (false, true) => Ok((
+                declared_type.unwrap_or(DataType::Char),
+                // ncgen joins the pieces of a char attribute together
+                AttributeValue::Text(texts.concat()),
+            )),
+            (true, false) => Ok((
+                declared_type.or(number_type).unwrap_or(DataType::Int),
+                AttributeValue::Numbers(numbers),
+            )),
+            _ => Err(invalid(line, "attribute mixes text and numbers")),
+        }
+    }
+
+    fn variables(&mut self, cdl: &mut ParsedCdl) -> Result<(), NcasCdlError> {
+        while !self.at_section() {
+            let line = self.line();
+            // Attributes are `var:name = ...` or `:name = ...` for global ones, and may
+            // start with their type, e.g. `string var:name = ...`
+            let (declared_type, target) = if self.at_symbol(':') {
+                (None, None)
+            } else {
+                let first = self.expect_name()?;
+                match (self.peek(), self.peek_at(1)) {
+                    (Some(Token::Symbol(':')), _) => match DataType::from_cdl_name(&first) {
+                        Some(data_type) => (Some(data_type), None),
+                        None => (None, Some(first)),
+                    },
+                    (Some(Token::Name(_)), Some(Token::Symbol(':'))) => {
+                        let data_type = DataType::from_cdl_name(&first).ok_or_else(|| {
+                            invalid(line, format!("{} is not a NetCDF type", first))
+                        })?;
+                        (Some(data_type), Some(self.expect_name()?))
+                    }
+                    _ => {
+                        // A variable declaration, `type name(dims) ;`
+                        let data_type = DataType::from_cdl_name(&first).ok_or_else(|| {
+                            invalid(line, format!("{} is not a NetCDF type", first))
+                        })?;
+                        let name = self.expect_name()?;
+                        let mut dimensions = Vec::new();
+                        if self.at_symbol('(') {
+                            self.next()?;
+                            while !self.at_symbol(')') {
+                                dimensions.push(self.expect_name()?);
+                                if self.at_symbol(',') {
+                                    self.next()?;
+                                }
+                            }
+                            self.next()?;
+                        }
+                        self.expect_symbol(';')?;
+                        cdl.variables.push(ParsedVariable {
+                            name,
+                            data_type,
+                            dimensions,
+                            attributes: Vec::new(),
+                        });
+                        continue;
+                    }
+                }
+            };
+            self.expect_symbol(':')?;
+            let name = self.expect_name()?;
+            self.expect_symbol('=')?;
+            let (data_type, value) = self.attribute_value(declared_type)?;
+            let attribute = ParsedAttribute {
+                name,
+                data_type,
+                value,
+            };
+            match target {
+                None => cdl.global_attributes.push(attribute),
+                Some(target) => match cdl.variables.iter_mut().find(|v| v.name == target) {
+                    Some(variable) => variable.attributes.push(attribute),
+                    None => {
+                        return Err(invalid(
+                            line,
+                            format!("attribute for undeclared variable {}", target),
+                        ));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn cdl(&mut self) -> Result<ParsedCdl, NcasCdlError> {
+        let mut cdl = ParsedCdl::default();
+        let line = self.line();
+        match self.next()? {
+            Token::Name(keyword) if keyword == "netcdf" => {}
+            token => {
+                return Err(invalid(
+                    line,
+                    format!("expected netcdf but found {}", token),
+                ));
+            }
+        }
+        cdl.name = self.expect_name()?;
+        self.expect_symbol('{')?;
+        loop {
+            let line = self.line();
+            match self.next()? {
+                Token::Symbol('}') => break,
+                Token::Name(section) => {
+                    self.expect_symbol(':')?;
+                    match section.as_str() {
+                        "dimensions" => self.dimensions(&mut cdl)?,
+                        "variables" => self.variables(&mut cdl)?,
+                        // Only the header is checked
+                        "data" => break,
+                        section => {
+                            return Err(invalid(
+                                line,
+                                format!("{} sections are not supported", section),
+                            ));
+                        }
+                    }
+                }
+                token => return Err(invalid(line, format!("unexpected {}", token))),
+            }
+        }
+        Ok(cdl)
+    }
+}
+
+/// Parses the header of a CDL file.
+pub fn parse_cdl(cdl: &str) -> Result<ParsedCdl, NcasCdlError> {
+    let mut parser = Parser {
+        tokens: tokenize(cdl)?,
+        position: 0,
+    };
+    parser.cdl()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title(cdl_text: &str) -> Result<AttributeValue, NcasCdlError> {
+        let cdl = format!(
+            "netcdf test {{\nvariables:\n// global attributes:\n\t\t:title = {} ;\n}}\n",
+            cdl_text
+        );
+        let mut parsed = parse_cdl(&cdl)?;
+        Ok(parsed.global_attributes.remove(0).value)
+    }
+
+    #[test]
+    fn hex_escapes_are_bytes_of_utf8() {
+        assert_eq!(
+            title(r#""caf\xc3\xa9 \"x\"\t\\""#).unwrap(),
+            AttributeValue::Text("café \"x\"\t\\".to_string())
+        );
+        assert_eq!(
+            title(r#""bell\x07""#).unwrap(),
+            AttributeValue::Text("bell\x07".to_string())
+        );
+        assert!(matches!(
+            title(r#""caf\xc3""#),
+            Err(NcasCdlError::InvalidCdl { line: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn placeholder_lengths_are_read_as_unlimited() {
+        let cdl = "netcdf test {\ndimensions:\n\ttime = <dim length> ;\n\tlatitude = 1 ;\n}\n";
+        let parsed = parse_cdl(cdl).unwrap();
+        assert_eq!(parsed.dimensions[0].length, None);
+        assert_eq!(parsed.dimensions[1].length, Some(1));
+        assert_eq!(
+            parsed.warnings,
+            ["Line 3: time has the length <dim length>, so was read as UNLIMITED"]
+        );
+    }
+
+    #[test]
+    fn placeholders_are_only_allowed_for_lengths() {
+        assert!(matches!(
+            title("<title>"),
+            Err(NcasCdlError::InvalidCdl { line: 4, .. })
+        ));
+        assert!(matches!(
+            parse_cdl("netcdf test {\ndimensions:\n\ttime = <dim length ;\n}\n"),
+            Err(NcasCdlError::InvalidCdl { line: 3, .. })
+        ));
+    }
+}
@@ -1,6 +1,11 @@
+use super::cv_source::{CvRepository, CvSource};
+use super::error::NcasCdlError;
 use super::netcdf_components::GlobalAttribute;
+use super::rules::WholeValueRegex;
 use serde::Deserialize;
-use std::error::Error;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::warn;
 
 #[derive(Debug, Deserialize)]
 struct CommonGlobalAttrsCV {
@@ -22,8 +27,10 @@ struct CommonGlobalAttrsCV {
         deserialize_with = "csv::invalid_option"
     )]
     pub convention_providence: Option<String>,
+    // Older tags don't have this column
     #[serde(
         rename = "Regex check (if required) - AS & JS to populate Vocabulary",
+        default,
         deserialize_with = "csv::invalid_option"
     )]
     pub regex_check: Option<String>,
@@ -34,29 +41,151 @@ pub struct Common {
     pub global_attributes: Vec<GlobalAttribute>,
 }
 
-async fn get_common_global_attrs(tag: &str) -> Result<Vec<GlobalAttribute>, Box<dyn Error>> {
-    let file_path = "https://raw.githubusercontent.com/ncasuk/AMF_CVs/".to_owned()
-        + tag
-        + "/product-definitions/tsv/_common/global-attributes.tsv";
-    let res = reqwest::get(&file_path).await?;
-    let ga_data = res.text().await?;
-    let mut lines = ga_data.lines();
-    lines.next(); // Discard the first line
+/// The regex checks the common global attributes have, compiled once for each tag.
+///
+/// Checks are keyed on their pattern as well as the tag, so a branch whose CVs change
+/// gets its new patterns compiled rather than the old ones reused.
+#[derive(Default)]
+pub struct RegexCache {
+    // A pattern that doesn't compile is kept as None so it is only reported once
+    compiled: Mutex<HashMap<(String, String), Option<WholeValueRegex>>>,
+}
+
+impl RegexCache {
+    fn get(&self, tag: &str, attr_name: &str, pattern: &str) -> Option<WholeValueRegex> {
+        let mut compiled = self.compiled.lock().unwrap();
+        compiled
+            .entry((tag.to_string(), pattern.to_string()))
+            .or_insert_with(|| {
+                WholeValueRegex::new(pattern)
+                    .inspect_err(|err| {
+                        warn!(
+                            "Ignoring the regex check for {} at {}, which does not compile: {}",
+                            attr_name, tag, err
+                        )
+                    })
+                    .ok()
+            })
+            .clone()
+    }
+}
+
+async fn get_common_global_attrs(
+    source: &dyn CvSource,
+    tag: &str,
+    regexes: &RegexCache,
+) -> Result<Vec<GlobalAttribute>, NcasCdlError> {
+    let file_path = "product-definitions/tsv/_common/global-attributes.tsv";
+    // Every tag has this file, so if it is missing the tag itself must not exist
+    let ga_data = source
+        .fetch(CvRepository::AmfCvs, tag, file_path)
+        .await
+        .map_err(|err| {
+            err.when_not_found(|location| NcasCdlError::UnknownTag {
+                tag: tag.to_string(),
+                location,
+            })
+        })?;
     let mut attrs: Vec<GlobalAttribute> = Vec::new();
-    for line in lines {
-        let parts = line.split('\t').collect::<Vec<&str>>();
-        let attr = GlobalAttribute {
-            name: parts.first().unwrap_or(&"").to_string(),
-            value: parts.get(3).unwrap_or(&"").to_string(),
-            example: parts.get(2).unwrap_or(&"").to_string(),
-            compliance: parts.get(4).unwrap_or(&"").to_string(),
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .flexible(true)
+        // Values such as titles can contain quotes, which are part of the value
+        .quoting(false)
+        .from_reader(ga_data.as_bytes());
+    for (index, result) in rdr.deserialize().enumerate() {
+        let record: CommonGlobalAttrsCV =
+            result.map_err(|err| NcasCdlError::malformed_csv(file_path, err))?;
+        let Some(name) = record.name else {
+            return Err(NcasCdlError::MalformedCv {
+                file: file_path.to_string(),
+                line: index as u64 + 2,
+                reason: "Can not find name for common global attribute".to_string(),
+            });
         };
-        attrs.push(attr);
+        let regex_check = record
+            .regex_check
+            .map(|pattern| pattern.trim().to_string())
+            .filter(|pattern| !pattern.is_empty())
+            .and_then(|pattern| regexes.get(tag, &name, &pattern));
+        attrs.push(GlobalAttribute {
+            name,
+            value: record.fixed_value.unwrap_or_default(),
+            example: record.example.unwrap_or_default(),
+            compliance: record.compliance_checking_rules.unwrap_or_default(),
+            regex_check,
+        });
     }
     Ok(attrs)
 }
 
-pub async fn get_common(tag: String) -> Result<Common, Box<dyn Error>> {
-    let global_attributes = get_common_global_attrs(&tag).await?;
+pub async fn get_common(
+    source: &dyn CvSource,
+    tag: String,
+    regexes: &RegexCache,
+) -> Result<Common, NcasCdlError> {
+    let global_attributes = get_common_global_attrs(source, &tag, regexes).await?;
     Ok(Common { global_attributes })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::test_fixtures;
+
+    #[test]
+    fn regexes_are_compiled_once_per_tag_and_pattern() {
+        let regexes = RegexCache::default();
+        let first = regexes
+            .get("v2.1.0", "time_coverage_start", "\\d{4}")
+            .unwrap();
+        assert!(first.is_match("2024"));
+        assert!(!first.is_match("20240"));
+        regexes.get("v2.1.0", "date_created", "\\d{4}").unwrap();
+        assert_eq!(regexes.compiled.lock().unwrap().len(), 1);
+        // A branch can change its patterns, so they are keyed on both
+        regexes
+            .get("main", "time_coverage_start", "\\d{4}")
+            .unwrap();
+        regexes
+            .get("main", "time_coverage_start", "\\d{8}")
+            .unwrap();
+        assert_eq!(regexes.compiled.lock().unwrap().len(), 3);
+        // Patterns that don't compile are remembered as None
+        assert!(regexes.get("main", "title", "(").is_none());
+        assert!(regexes.get("main", "title", "(").is_none());
+        assert_eq!(regexes.compiled.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn common_attributes_have_their_regex_checks() {
+        let source = test_fixtures::cv_source();
+        let regexes = RegexCache::default();
+        for _ in 0..2 {
+            let common = get_common(&source, test_fixtures::TAG.to_string(), &regexes)
+                .await
+                .unwrap();
+            let checks: Vec<(&str, Option<&str>)> = common
+                .global_attributes
+                .iter()
+                .map(|attr| {
+                    let check = attr.regex_check.as_ref().map(|regex| regex.pattern());
+                    (attr.name.as_str(), check)
+                })
+                .collect();
+            assert_eq!(
+                checks,
+                [
+                    ("Conventions", None),
+                    ("creator_name", None),
+                    (
+                        "time_coverage_start",
+                        Some("\\d{4}-\\d{2}-\\d{2}T\\d{2}:\\d{2}:\\d{2}")
+                    ),
+                    ("title", None),
+                ]
+            );
+        }
+        assert_eq!(regexes.compiled.lock().unwrap().len(), 1);
+    }
+}
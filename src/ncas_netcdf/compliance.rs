@@ -0,0 +1,436 @@
+use super::cdl_parser::{ParsedAttribute, ParsedCdl};
+use super::rules::{CheckStatus, Rule};
+use super::template::{
+    AttributeValue, DataType, Number, RequirementLevel, Template, TemplateAttribute,
+    numeric_attribute_type,
+};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    WrongName,
+    MissingDimension,
+    WrongDimensionLength,
+    MissingVariable,
+    WrongDimensions,
+    MissingAttribute,
+    WrongType,
+    WrongValue,
+    /// A value the standard doesn't fix failed the CVs' regex check or compliance rule.
+    RuleFailed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComplianceProblem {
+    pub kind: ProblemKind,
+    /// What the problem is with, e.g. `time`, `time:units`, or `:title` for a global
+    /// attribute.
+    pub location: String,
+    pub message: String,
+    pub expected: Option<String>,
+    pub found: Option<String>,
+}
+
+/// How a CDL file compares to the template for its instrument and data product.
+#[derive(Debug, Serialize)]
+pub struct ComplianceReport {
+    pub compliant: bool,
+    pub problems: Vec<ComplianceProblem>,
+}
+
+fn value_text(value: &AttributeValue) -> String {
+    match value {
+        AttributeValue::Text(text) => text.clone(),
+        AttributeValue::Numbers(numbers) => numbers
+            .iter()
+            .map(|number| number.to_string())
+            .collect::<Vec<String>>()
+            .join(", "),
+    }
+}
+
+fn number_value(number: &Number) -> f64 {
+    match number {
+        Number::Signed(v) => *v as f64,
+        Number::Unsigned(v) => *v as f64,
+        Number::Float(v) => *v,
+    }
+}
+
+// Floats in the file may have been rounded to single precision, so compare loosely
+fn values_match(expected: &AttributeValue, found: &AttributeValue) -> bool {
+    match (expected, found) {
+        (AttributeValue::Text(expected), AttributeValue::Text(found)) => {
+            expected.trim() == found.trim()
+        }
+        (AttributeValue::Numbers(expected), AttributeValue::Numbers(found)) => {
+            expected.len() == found.len()
+                && expected.iter().zip(found).all(|(expected, found)| {
+                    let (expected, found) = (number_value(expected), number_value(found));
+                    expected == found
+                        || (expected - found).abs() <= 1e-6 * expected.abs().max(found.abs())
+                })
+        }
+        _ => false,
+    }
+}
+
+struct Checker {
+    problems: Vec<ComplianceProblem>,
+}
+
+impl Checker {
+    fn problem(
+        &mut self,
+        kind: ProblemKind,
+        location: String,
+        message: String,
+        expected: Option<String>,
+        found: Option<String>,
+    ) {
+        self.problems.push(ComplianceProblem {
+            kind,
+            location,
+            message,
+            expected,
+            found,
+        });
+    }
+
+    // `numeric_type` is the type the attribute should have when it is a number whose
+    // value the template doesn't fix, e.g. valid_min
+    fn attribute(
+        &mut self,
+        location: String,
+        expected: &TemplateAttribute,
+        found: Option<&ParsedAttribute>,
+        numeric_type: Option<DataType>,
+    ) {
+        let Some(found) = found else {
+            self.problem(
+                ProblemKind::MissingAttribute,
+                location.clone(),
+                format!("{} is missing", location),
+                expected.fixed.then(|| value_text(&expected.value)),
+                None,
+            );
+            return;
+        };
+        let expected_type = match &expected.value {
+            AttributeValue::Numbers(_) => Some(expected.data_type),
+            AttributeValue::Text(_) => numeric_type,
+        };
+        let type_matches = match expected_type {
+            Some(expected_type) => found.data_type == expected_type,
+            None => matches!(found.data_type, DataType::Char | DataType::String),
+        };
+        if !type_matches {
+            let expected_name = expected_type.map_or("text", |t| t.name());
+            self.problem(
+                ProblemKind::WrongType,
+                location.clone(),
+                format!(
+                    "{} should be {} but is {}",
+                    location,
+                    expected_name,
+                    found.data_type.name()
+                ),
+                Some(expected_name.to_string()),
+                Some(found.data_type.name().to_string()),
+            );
+        } else if expected.fixed && !values_match(&expected.value, &found.value) {
+            self.problem(
+                ProblemKind::WrongValue,
+                location.clone(),
+                format!("{} does not have the value the standard requires", location),
+                Some(value_text(&expected.value)),
+                Some(value_text(&found.value)),
+            );
+        } else if let (false, AttributeValue::Text(text)) = (expected.fixed, &found.value) {
+            self.rules(location, expected, text);
+        }
+    }
+
+    // Text the standard doesn't fix is checked with the CVs' regex check and rule instead
+    fn rules(&mut self, location: String, expected: &TemplateAttribute, text: &str) {
+        let failed_regex = expected
+            .regex_check
+            .as_ref()
+            .filter(|regex| !regex.is_match(text));
+        if let Some(regex) = failed_regex {
+            self.problem(
+                ProblemKind::RuleFailed,
+                location.clone(),
+                format!("{} does not match {}", location, regex.pattern()),
+                Some(format!("regex check: {}", regex.pattern())),
+                Some(text.to_string()),
+            );
+            return;
+        }
+        let Some(rule) = &expected.rule else {
+            return;
+        };
+        if let (CheckStatus::Fail, Some(reason)) = Rule::parse(rule).check(text) {
+            self.problem(
+                ProblemKind::RuleFailed,
+                location.clone(),
+                format!("{} {}", location, reason),
+                Some(rule.clone()),
+                Some(text.to_string()),
+            );
+        }
+    }
+}
+
+fn find_attribute<'a>(
+    attributes: &'a [ParsedAttribute],
+    name: &str,
+) -> Option<&'a ParsedAttribute> {
+    attributes.iter().find(|attr| attr.name == name)
+}
+
+/// Checks CDL against a template: that everything required is there, and that what is
+/// there has the right types and any values the standard fixes. Optional variables are
+/// only checked when present. The file's name is only checked with `check_name`.
+pub fn check_cdl(template: &Template, cdl: &ParsedCdl, check_name: bool) -> ComplianceReport {
+    let mut checker = Checker {
+        problems: Vec::new(),
+    };
+
+    let expected_name = template
+        .filename
+        .strip_suffix(".nc")
+        .unwrap_or(&template.filename);
+    if check_name && cdl.name != expected_name {
+        checker.problem(
+            ProblemKind::WrongName,
+            cdl.name.clone(),
+            format!("The file should be named {}", template.filename),
+            Some(expected_name.to_string()),
+            Some(cdl.name.clone()),
+        );
+    }
+
+    for dimension in &template.dimensions {
+        let found = cdl.dimensions.iter().find(|d| d.name == dimension.name);
+        match (found, dimension.length) {
+            (None, _) => checker.problem(
+                ProblemKind::MissingDimension,
+                dimension.name.clone(),
+                format!("Dimension {} is missing", dimension.name),
+                dimension.length.map(|length| length.to_string()),
+                None,
+            ),
+            (Some(found), Some(length)) if found.length != Some(u64::from(length)) => checker
+                .problem(
+                    ProblemKind::WrongDimensionLength,
+                    dimension.name.clone(),
+                    format!("Dimension {} should have length {}", dimension.name, length),
+                    Some(length.to_string()),
+                    Some(
+                        found
+                            .length
+                            .map_or("UNLIMITED".to_string(), |l| l.to_string()),
+                    ),
+                ),
+            _ => {}
+        }
+    }
+
+    for variable in &template.variables {
+        let Some(found) = cdl.variables.iter().find(|v| v.name == variable.name) else {
+            if !matches!(variable.requirement, RequirementLevel::Optional) {
+                checker.problem(
+                    ProblemKind::MissingVariable,
+                    variable.name.clone(),
+                    format!("Variable {} is missing", variable.name),
+                    None,
+                    None,
+                );
+            }
+            continue;
+        };
+        if found.data_type != variable.data_type {
+            checker.problem(
+                ProblemKind::WrongType,
+                variable.name.clone(),
+                format!(
+                    "Variable {} should be {} but is {}",
+                    variable.name,
+                    variable.data_type.name(),
+                    found.data_type.name()
+                ),
+                Some(variable.data_type.name().to_string()),
+                Some(found.data_type.name().to_string()),
+            );
+        }
+        if found.dimensions != variable.dimensions {
+            checker.problem(
+                ProblemKind::WrongDimensions,
+                variable.name.clone(),
+                format!(
+                    "Variable {} should have dimensions ({})",
+                    variable.name,
+                    variable.dimensions.join(", ")
+                ),
+                Some(variable.dimensions.join(", ")),
+                Some(found.dimensions.join(", ")),
+            );
+        }
+        for attr in &variable.attributes {
+            checker.attribute(
+                format!("{}:{}", variable.name, attr.name),
+                attr,
+                find_attribute(&found.attributes, &attr.name),
+                numeric_attribute_type(&attr.name, variable.data_type),
+            );
+        }
+    }
+
+    for attr in &template.global_attributes {
+        checker.attribute(
+            format!(":{}", attr.name),
+            attr,
+            find_attribute(&cdl.global_attributes, &attr.name),
+            None,
+        );
+    }
+
+    ComplianceReport {
+        compliant: checker.problems.is_empty(),
+        problems: checker.problems,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::cdl::{CdlOptions, make_cdl};
+    use crate::ncas_netcdf::cdl_parser::parse_cdl;
+    use crate::ncas_netcdf::test_fixtures;
+    use indexmap::IndexMap;
+
+    // What `ncdump -h` prints for a file made from the fixture template, with a few
+    // mistakes in it. The data product's count variable is optional, so can be left out.
+    const NCDUMP_HEADER: &str = r#"netcdf ncas-ceilometer-3_cao_20240101_aerosol-backscatter_v1.0 {
+dimensions:
+	time = UNLIMITED ; // (1440 currently)
+	latitude = 1 ;
+	altitude = 200 ;
+variables:
+	double time(time) ;
+		time:units = "seconds since 1970-01-01 00:00:00" ;
+		time:standard_name = "time" ;
+	float altitude(time) ;
+		altitude:units = "m" ;
+		altitude:valid_min = 0.5 ;
+		altitude:_FillValue = -1.e+20f ;
+	byte qc_flag(time, altitude) ;
+		qc_flag:flag_values = 0b, 1b, 2b ;
+		qc_flag:flag_meanings = "not_used good_data bad_data" ;
+		qc_flag:_FillValue = 0b ;
+
+// global attributes:
+		:Conventions = "CF-1.6" ;
+		:creator_name = "Jane Doe" ;
+		:time_coverage_start = "2024-01-01T00:00:00" ;
+		:title = "Short" ;
+		string :laser_wavelength = "905 nm" ;
+}
+"#;
+
+    // Checked against the template without values, as /validate-cdl does
+    async fn template() -> Template {
+        test_fixtures::cdl_data(IndexMap::new()).await.template
+    }
+
+    #[tokio::test]
+    async fn generated_cdl_is_compliant() {
+        // A title that has to be escaped, and is long enough to be wrapped
+        let title = "Backscatter from the \"CL51\" ceilometer at Chilbolton, Hampshire \
+                     (été 2024)\nSecond line with a C:\\path and a bell\x07";
+        let mut values = test_fixtures::attribute_values();
+        values.insert("title".to_string(), title.to_string());
+        let cdl_data = test_fixtures::cdl_data(values).await;
+        let template = template().await;
+        for options in [
+            CdlOptions::default(),
+            CdlOptions {
+                requirement_info: true,
+                wrap_long_strings: true,
+            },
+        ] {
+            let parsed = parse_cdl(&make_cdl(&cdl_data.template, &options)).unwrap();
+            // The lengths of time and altitude are left as placeholders
+            assert_eq!(parsed.warnings.len(), 2, "{:?}", parsed.warnings);
+            let parsed_title = find_attribute(&parsed.global_attributes, "title").unwrap();
+            assert_eq!(parsed_title.value, AttributeValue::Text(title.to_string()));
+            let report = check_cdl(&template, &parsed, true);
+            assert!(report.problems.is_empty(), "{:?}", report.problems);
+            assert!(report.compliant);
+        }
+    }
+
+    #[tokio::test]
+    async fn ncdump_header_parses() {
+        let parsed = parse_cdl(NCDUMP_HEADER).unwrap();
+        assert_eq!(
+            parsed.name,
+            "ncas-ceilometer-3_cao_20240101_aerosol-backscatter_v1.0"
+        );
+        let dimensions: Vec<(&str, Option<u64>)> = parsed
+            .dimensions
+            .iter()
+            .map(|d| (d.name.as_str(), d.length))
+            .collect();
+        assert_eq!(
+            dimensions,
+            [
+                ("time", None),
+                ("latitude", Some(1)),
+                ("altitude", Some(200))
+            ]
+        );
+        let qc_flag = &parsed.variables[2];
+        assert_eq!(qc_flag.data_type, DataType::Byte);
+        assert_eq!(qc_flag.dimensions, ["time", "altitude"]);
+        assert_eq!(qc_flag.attributes[0].data_type, DataType::Byte);
+        assert_eq!(parsed.global_attributes.len(), 5);
+        assert_eq!(parsed.global_attributes[4].data_type, DataType::String);
+    }
+
+    #[tokio::test]
+    async fn reports_what_differs_from_the_template() {
+        let parsed = parse_cdl(NCDUMP_HEADER).unwrap();
+        let report = check_cdl(&template().await, &parsed, true);
+        let problems: Vec<(ProblemKind, &str)> = report
+            .problems
+            .iter()
+            .map(|problem| (problem.kind, problem.location.as_str()))
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                (ProblemKind::MissingVariable, "latitude"),
+                (ProblemKind::WrongType, "altitude:valid_min"),
+                (ProblemKind::WrongValue, ":Conventions"),
+                (ProblemKind::RuleFailed, ":title"),
+            ]
+        );
+        assert!(!report.compliant);
+    }
+
+    #[tokio::test]
+    async fn file_name_is_only_checked_when_asked() {
+        let cdl_data = test_fixtures::cdl_data(test_fixtures::attribute_values()).await;
+        let cdl = make_cdl(&cdl_data.template, &CdlOptions::default())
+            .replacen("20240101", "20240102", 1);
+        let parsed = parse_cdl(&cdl).unwrap();
+        let template = template().await;
+        assert!(check_cdl(&template, &parsed, false).compliant);
+        let report = check_cdl(&template, &parsed, true);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.problems[0].kind, ProblemKind::WrongName);
+    }
+}
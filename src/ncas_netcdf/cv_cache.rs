@@ -0,0 +1,314 @@
+use super::cv_source::{CvDirEntry, CvRepository, CvSource};
+use super::error::NcasCdlError;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum CacheKind {
+    File,
+    Listing,
+}
+
+type CacheKey = (CacheKind, CvRepository, String, String);
+
+#[derive(Clone)]
+enum CachedValue {
+    File(String),
+    Listing(Vec<CvDirEntry>),
+}
+
+impl CachedValue {
+    fn size(&self) -> usize {
+        match self {
+            CachedValue::File(contents) => contents.len(),
+            CachedValue::Listing(entries) => entries.iter().map(|entry| entry.name.len()).sum(),
+        }
+    }
+}
+
+struct CacheEntry {
+    value: CachedValue,
+    fetched: Instant,
+    last_used: Instant,
+    mutable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    /// How long files from branches (anything that isn't a release tag) are kept.
+    pub mutable_ttl: Duration,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        CacheLimits {
+            max_entries: 256,
+            max_bytes: 64 * 1024 * 1024,
+            mutable_ttl: Duration::from_secs(300),
+        }
+    }
+}
+
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    total_bytes: usize,
+}
+
+/// Keeps files and directory listings fetched from another CvSource in memory, keyed on
+/// (repository, tag, path).
+///
+/// Release tags such as `v2.1.0` never change, so their files are kept until evicted
+/// to stay within the size limits. Files from other refs (e.g. `refs/heads/main`) are
+/// re-fetched once they are older than `mutable_ttl`.
+pub struct CachedCvSource {
+    inner: Arc<dyn CvSource>,
+    limits: CacheLimits,
+    state: Mutex<CacheState>,
+}
+
+/// Whether a ref can move, i.e. is not a release tag like `v2.1.0` or `refs/tags/v2.1.0`.
+fn is_mutable_ref(tag: &str) -> bool {
+    let tag = tag.strip_prefix("refs/tags/").unwrap_or(tag);
+    let version = tag.strip_prefix('v').unwrap_or(tag);
+    !version.starts_with(|c: char| c.is_ascii_digit())
+}
+
+impl CachedCvSource {
+    pub fn new(inner: Arc<dyn CvSource>, limits: CacheLimits) -> Self {
+        CachedCvSource {
+            inner,
+            limits,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                total_bytes: 0,
+            }),
+        }
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<CachedValue> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.entries.get_mut(key)?;
+        if entry.mutable && entry.fetched.elapsed() > self.limits.mutable_ttl {
+            let size = entry.value.size();
+            state.entries.remove(key);
+            state.total_bytes -= size;
+            return None;
+        }
+        entry.last_used = Instant::now();
+        Some(entry.value.clone())
+    }
+
+    fn insert(&self, key: CacheKey, value: CachedValue) {
+        let size = value.size();
+        if size > self.limits.max_bytes || self.limits.max_entries == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let mutable = is_mutable_ref(&key.2);
+        if let Some(old) = state.entries.remove(&key) {
+            state.total_bytes -= old.value.size();
+        }
+        // Evict least recently used entries until the new file fits
+        while state.entries.len() >= self.limits.max_entries
+            || state.total_bytes + size > self.limits.max_bytes
+        {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest.and_then(|key| state.entries.remove(&key)) {
+                Some(entry) => state.total_bytes -= entry.value.size(),
+                None => break,
+            }
+        }
+        let now = Instant::now();
+        state.total_bytes += size;
+        state.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                fetched: now,
+                last_used: now,
+                mutable,
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl CvSource for CachedCvSource {
+    async fn fetch(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<String, NcasCdlError> {
+        let key = (
+            CacheKind::File,
+            repository,
+            tag.to_string(),
+            path.to_string(),
+        );
+        if let Some(CachedValue::File(contents)) = self.get(&key) {
+            return Ok(contents);
+        }
+        let contents = self.inner.fetch(repository, tag, path).await?;
+        self.insert(key, CachedValue::File(contents.clone()));
+        Ok(contents)
+    }
+
+    async fn list(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<Vec<CvDirEntry>, NcasCdlError> {
+        let key = (
+            CacheKind::Listing,
+            repository,
+            tag.to_string(),
+            path.to_string(),
+        );
+        if let Some(CachedValue::Listing(entries)) = self.get(&key) {
+            return Ok(entries);
+        }
+        let entries = self.inner.list(repository, tag, path).await?;
+        self.insert(key, CachedValue::Listing(entries.clone()));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::cv_source::MemoryCvSource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts the fetches that get past the cache
+    struct CountingSource {
+        inner: MemoryCvSource,
+        fetches: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl CvSource for CountingSource {
+        async fn fetch(
+            &self,
+            repository: CvRepository,
+            tag: &str,
+            path: &str,
+        ) -> Result<String, NcasCdlError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            self.inner.fetch(repository, tag, path).await
+        }
+
+        async fn list(
+            &self,
+            repository: CvRepository,
+            tag: &str,
+            path: &str,
+        ) -> Result<Vec<CvDirEntry>, NcasCdlError> {
+            self.inner.list(repository, tag, path).await
+        }
+    }
+
+    const TAG: &str = "v2.1.0";
+    const BRANCH: &str = "refs/heads/main";
+
+    fn cache(limits: CacheLimits) -> (Arc<CountingSource>, CachedCvSource) {
+        let mut inner = MemoryCvSource::new();
+        for tag in [TAG, BRANCH] {
+            for (path, contents) in [("a.tsv", "aaaa"), ("b.tsv", "bbbb"), ("c.tsv", "cccc")] {
+                inner.insert(CvRepository::AmfCvs, tag, path, contents);
+            }
+        }
+        let counting = Arc::new(CountingSource {
+            inner,
+            fetches: AtomicUsize::new(0),
+        });
+        let cached = CachedCvSource::new(counting.clone(), limits);
+        (counting, cached)
+    }
+
+    impl CachedCvSource {
+        async fn fetch_all(&self, tag: &str, paths: &[&str]) {
+            for path in paths {
+                self.fetch(CvRepository::AmfCvs, tag, path).await.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn only_version_tags_are_immutable() {
+        assert!(!is_mutable_ref("v2.1.0"));
+        assert!(!is_mutable_ref("2.1.0"));
+        assert!(!is_mutable_ref("refs/tags/v2.1.0"));
+        assert!(is_mutable_ref("refs/heads/main"));
+        assert!(is_mutable_ref("main"));
+    }
+
+    #[tokio::test]
+    async fn files_are_fetched_once() {
+        let (counting, cached) = cache(CacheLimits::default());
+        cached
+            .fetch_all(TAG, &["a.tsv", "b.tsv", "a.tsv", "b.tsv"])
+            .await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 2);
+        // The same path at another ref is a different file
+        cached.fetch_all(BRANCH, &["a.tsv"]).await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_files_are_evicted() {
+        let (counting, cached) = cache(CacheLimits {
+            max_entries: 2,
+            ..CacheLimits::default()
+        });
+        cached
+            .fetch_all(TAG, &["a.tsv", "b.tsv", "a.tsv", "c.tsv"])
+            .await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 3);
+        // b.tsv was used least recently, so made way for c.tsv
+        cached.fetch_all(TAG, &["a.tsv", "c.tsv"]).await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 3);
+        cached.fetch_all(TAG, &["b.tsv"]).await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn files_are_evicted_to_stay_within_max_bytes() {
+        let (counting, cached) = cache(CacheLimits {
+            max_bytes: 8,
+            ..CacheLimits::default()
+        });
+        cached.fetch_all(TAG, &["a.tsv", "b.tsv", "c.tsv"]).await;
+        assert_eq!(cached.state.lock().unwrap().total_bytes, 8);
+        cached.fetch_all(TAG, &["b.tsv", "c.tsv"]).await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 3);
+        cached.fetch_all(TAG, &["a.tsv"]).await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn files_from_branches_expire() {
+        let (counting, cached) = cache(CacheLimits {
+            mutable_ttl: Duration::from_millis(20),
+            ..CacheLimits::default()
+        });
+        cached.fetch_all(TAG, &["a.tsv"]).await;
+        cached.fetch_all(BRANCH, &["a.tsv"]).await;
+        cached.fetch_all(BRANCH, &["a.tsv"]).await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        // Only the file from the branch is fetched again
+        cached.fetch_all(TAG, &["a.tsv"]).await;
+        cached.fetch_all(BRANCH, &["a.tsv"]).await;
+        assert_eq!(counting.fetches.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,425 @@
+use super::error::NcasCdlError;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+
+/// Repositories under the ncasuk GitHub organisation that hold controlled vocabularies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CvRepository {
+    AmfCvs,
+    InstrumentVocabs,
+}
+
+impl CvRepository {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CvRepository::AmfCvs => "AMF_CVs",
+            CvRepository::InstrumentVocabs => "ncas-data-instrument-vocabs",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CvDirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// Somewhere controlled vocabulary files can be read from.
+///
+/// `tag` is a git ref (e.g. `v2.1.0` or `refs/heads/main`) and `path` is relative to the
+/// root of the repository.
+#[async_trait]
+pub trait CvSource: Send + Sync {
+    async fn fetch(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<String, NcasCdlError>;
+
+    /// Lists the files and directories directly inside the directory at `path`.
+    async fn list(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<Vec<CvDirEntry>, NcasCdlError>;
+}
+
+/// Reads files from raw.githubusercontent.com, and directory listings from the GitHub API.
+pub struct GitHubCvSource {
+    client: reqwest::Client,
+    base_url: String,
+    api_url: String,
+}
+
+#[derive(Deserialize)]
+struct GitHubContent {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl GitHubCvSource {
+    pub fn new() -> Self {
+        GitHubCvSource {
+            // The GitHub API rejects requests without a User-Agent
+            client: reqwest::Client::builder()
+                .user_agent("ncas-general-cdl-creator-backend")
+                .build()
+                .unwrap_or_default(),
+            base_url: "https://raw.githubusercontent.com/ncasuk".to_string(),
+            api_url: "https://api.github.com/repos/ncasuk".to_string(),
+        }
+    }
+
+    pub fn url(&self, repository: CvRepository, tag: &str, path: &str) -> String {
+        format!("{}/{}/{}/{}", self.base_url, repository.name(), tag, path)
+    }
+
+    fn contents_url(&self, repository: CvRepository, tag: &str, path: &str) -> String {
+        format!(
+            "{}/{}/contents/{}?ref={}",
+            self.api_url,
+            repository.name(),
+            path,
+            tag
+        )
+    }
+
+    async fn get_text(&self, url: String) -> Result<String, NcasCdlError> {
+        let unavailable = |err: reqwest::Error| NcasCdlError::UpstreamUnavailable {
+            location: url.clone(),
+            reason: err.to_string(),
+        };
+        let res = self.client.get(&url).send().await.map_err(unavailable)?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(NcasCdlError::CvFileNotFound { location: url });
+        }
+        if !res.status().is_success() {
+            return Err(NcasCdlError::UpstreamUnavailable {
+                location: url,
+                reason: format!("GitHub responded with {}", res.status()),
+            });
+        }
+        res.text().await.map_err(unavailable)
+    }
+}
+
+impl Default for GitHubCvSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl CvSource for GitHubCvSource {
+    async fn fetch(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<String, NcasCdlError> {
+        self.get_text(self.url(repository, tag, path)).await
+    }
+
+    async fn list(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<Vec<CvDirEntry>, NcasCdlError> {
+        let url = self.contents_url(repository, tag, path);
+        let body = self.get_text(url.clone()).await?;
+        let contents: Vec<GitHubContent> =
+            serde_json::from_str(&body).map_err(|err| NcasCdlError::UpstreamUnavailable {
+                location: url,
+                reason: format!("Unexpected directory listing: {}", err),
+            })?;
+        Ok(contents
+            .into_iter()
+            .map(|content| CvDirEntry {
+                name: content.name,
+                is_dir: content.kind == "dir",
+            })
+            .collect())
+    }
+}
+
+/// Reads files from local checkouts, laid out as `<root>/<repository name>/<tag>/<path>`,
+/// e.g. one `git worktree` for each tag. Branches are checked out under their short name,
+/// so `refs/heads/main` is read from `main`.
+pub struct LocalCvSource {
+    root: PathBuf,
+}
+
+impl LocalCvSource {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalCvSource { root: root.into() }
+    }
+
+    // Tags and paths include parts of requests, so anything that could lead outside the
+    // checkout, such as "..", is treated as a file that doesn't exist
+    fn resolve(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<PathBuf, NcasCdlError> {
+        let checkout = tag
+            .strip_prefix("refs/heads/")
+            .or_else(|| tag.strip_prefix("refs/tags/"))
+            .unwrap_or(tag);
+        let is_normal = |path: &Path| {
+            path.components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        };
+        let checkout = Path::new(checkout);
+        let inside =
+            checkout.components().count() == 1 && is_normal(checkout) && is_normal(Path::new(path));
+        if !inside {
+            return Err(NcasCdlError::CvFileNotFound {
+                location: format!("{}/{}/{}", repository.name(), tag, path),
+            });
+        }
+        Ok(self.root.join(repository.name()).join(checkout).join(path))
+    }
+}
+
+fn io_error(err: std::io::Error, path: &Path) -> NcasCdlError {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => NcasCdlError::CvFileNotFound {
+            location: path.display().to_string(),
+        },
+        _ => NcasCdlError::UpstreamUnavailable {
+            location: path.display().to_string(),
+            reason: err.to_string(),
+        },
+    }
+}
+
+#[async_trait]
+impl CvSource for LocalCvSource {
+    async fn fetch(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<String, NcasCdlError> {
+        let file_path = self.resolve(repository, tag, path)?;
+        tokio::fs::read_to_string(&file_path)
+            .await
+            .map_err(|err| io_error(err, &file_path))
+    }
+
+    async fn list(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<Vec<CvDirEntry>, NcasCdlError> {
+        let dir_path = self.resolve(repository, tag, path)?;
+        let mut dir = tokio::fs::read_dir(&dir_path)
+            .await
+            .map_err(|err| io_error(err, &dir_path))?;
+        let mut entries = Vec::new();
+        while let Some(entry) = dir
+            .next_entry()
+            .await
+            .map_err(|err| io_error(err, &dir_path))?
+        {
+            let file_type = entry
+                .file_type()
+                .await
+                .map_err(|err| io_error(err, &entry.path()))?;
+            entries.push(CvDirEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                is_dir: file_type.is_dir(),
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+}
+
+/// Serves files held in memory, keyed on repository, tag and path.
+#[derive(Default)]
+pub struct MemoryCvSource {
+    files: HashMap<(CvRepository, String, String), String>,
+}
+
+impl MemoryCvSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(
+        &mut self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+        contents: impl Into<String>,
+    ) {
+        self.files.insert(
+            (repository, tag.to_string(), path.to_string()),
+            contents.into(),
+        );
+    }
+}
+
+#[async_trait]
+impl CvSource for MemoryCvSource {
+    async fn fetch(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<String, NcasCdlError> {
+        match self
+            .files
+            .get(&(repository, tag.to_string(), path.to_string()))
+        {
+            Some(contents) => Ok(contents.clone()),
+            None => Err(NcasCdlError::CvFileNotFound {
+                location: format!("{}/{}/{}", repository.name(), tag, path),
+            }),
+        }
+    }
+
+    async fn list(
+        &self,
+        repository: CvRepository,
+        tag: &str,
+        path: &str,
+    ) -> Result<Vec<CvDirEntry>, NcasCdlError> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        // Directories only exist implicitly, as the parents of files
+        let mut children = BTreeMap::new();
+        for (file_repository, file_tag, file_path) in self.files.keys() {
+            if *file_repository != repository || file_tag != tag {
+                continue;
+            }
+            if let Some(rest) = file_path.strip_prefix(&prefix) {
+                match rest.split_once('/') {
+                    Some((dir, _)) => children.insert(dir.to_string(), true),
+                    None => children.insert(rest.to_string(), false),
+                };
+            }
+        }
+        if children.is_empty() {
+            return Err(NcasCdlError::CvFileNotFound {
+                location: format!("{}/{}/{}", repository.name(), tag, path),
+            });
+        }
+        Ok(children
+            .into_iter()
+            .map(|(name, is_dir)| CvDirEntry { name, is_dir })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "product-definitions/tsv/_common/global-attributes.tsv";
+
+    // A checkout of AMF_CVs at v2.1.0 and of the instrument vocabulary at main, in a
+    // directory of its own for each test
+    fn local_source(test: &str) -> LocalCvSource {
+        let root = std::env::temp_dir().join(format!("cv-source-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let files = [
+            (root.join("AMF_CVs/v2.1.0").join(PATH), "Name\n"),
+            (
+                root.join("ncas-data-instrument-vocabs/main/vocab.tsv"),
+                "Instrument\n",
+            ),
+            (root.join("secret.txt"), "secret\n"),
+        ];
+        for (path, contents) in files {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        LocalCvSource::new(root)
+    }
+
+    fn is_not_found<T>(result: Result<T, NcasCdlError>) -> bool {
+        matches!(result, Err(NcasCdlError::CvFileNotFound { .. }))
+    }
+
+    #[tokio::test]
+    async fn local_files_are_read_from_the_checkout_for_the_tag() {
+        let source = local_source("tags");
+        let contents = source.fetch(CvRepository::AmfCvs, "v2.1.0", PATH).await;
+        assert_eq!(contents.unwrap(), "Name\n");
+        let contents = source
+            .fetch(
+                CvRepository::InstrumentVocabs,
+                "refs/heads/main",
+                "vocab.tsv",
+            )
+            .await;
+        assert_eq!(contents.unwrap(), "Instrument\n");
+        assert!(is_not_found(
+            source.fetch(CvRepository::AmfCvs, "v2.0.0", PATH).await
+        ));
+        let entries = source
+            .list(CvRepository::AmfCvs, "v2.1.0", "product-definitions/tsv")
+            .await
+            .unwrap();
+        assert_eq!(
+            entries,
+            [CvDirEntry {
+                name: "_common".to_string(),
+                is_dir: true
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_paths_cannot_leave_the_checkout() {
+        let source = local_source("escape");
+        for (tag, path) in [
+            ("v2.1.0", "../../secret.txt"),
+            ("..", "../secret.txt"),
+            ("../..", "secret.txt"),
+            ("v2.1.0", "/etc/passwd"),
+            ("", "secret.txt"),
+        ] {
+            assert!(
+                is_not_found(source.fetch(CvRepository::AmfCvs, tag, path).await),
+                "{} {}",
+                tag,
+                path
+            );
+        }
+        assert!(is_not_found(
+            source.list(CvRepository::AmfCvs, "v2.1.0", "..").await
+        ));
+    }
+
+    #[tokio::test]
+    async fn memory_directories_are_the_parents_of_files() {
+        let mut source = MemoryCvSource::new();
+        source.insert(CvRepository::AmfCvs, "v2.1.0", "a/b/c.tsv", "c");
+        source.insert(CvRepository::AmfCvs, "v2.1.0", "a/d.tsv", "d");
+        source.insert(CvRepository::AmfCvs, "v2.0.0", "a/e.tsv", "e");
+        let entries = source
+            .list(CvRepository::AmfCvs, "v2.1.0", "a")
+            .await
+            .unwrap();
+        let entries: Vec<(&str, bool)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.is_dir))
+            .collect();
+        assert_eq!(entries, [("b", true), ("d.tsv", false)]);
+        assert!(is_not_found(
+            source
+                .fetch(CvRepository::AmfCvs, "v2.0.0", "a/d.tsv")
+                .await
+        ));
+    }
+}
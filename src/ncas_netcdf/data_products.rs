@@ -1,7 +1,9 @@
+use super::cv_source::{CvRepository, CvSource};
+use super::error::NcasCdlError;
 use super::netcdf_components::{Dimension, GlobalAttribute, Variable};
-use serde::Deserialize;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::error::Error;
 
 #[derive(Debug, Deserialize)]
 pub struct DataProduct {
@@ -55,37 +57,54 @@ pub struct VariableCV {
     pub example_value: Option<String>,
 }
 
+// A data product that doesn't exist at this tag shows up as a missing file
+fn unknown_data_product(err: NcasCdlError, data_product: &str, tag: &str) -> NcasCdlError {
+    err.when_not_found(|location| NcasCdlError::UnknownDataProduct {
+        name: data_product.to_string(),
+        tag: tag.to_string(),
+        location,
+    })
+}
+
 async fn get_data_product_global_attributes(
+    source: &dyn CvSource,
     data_product: &str,
     tag: &str,
-) -> Result<Vec<GlobalAttribute>, Box<dyn Error>> {
-    let file_path = "https://raw.githubusercontent.com/ncasuk/AMF_CVs/".to_owned()
-        + tag
-        + "/product-definitions/tsv/"
-        + data_product
-        + "/global-attributes-specific.tsv";
-    let res = reqwest::get(&file_path).await?;
-    let ga_data = res.text().await?;
+) -> Result<Vec<GlobalAttribute>, NcasCdlError> {
+    let file_path = format!(
+        "product-definitions/tsv/{}/global-attributes-specific.tsv",
+        data_product
+    );
+    let ga_data = source
+        .fetch(CvRepository::AmfCvs, tag, &file_path)
+        .await
+        .map_err(|err| unknown_data_product(err, data_product, tag))?;
     let mut attrs: Vec<GlobalAttribute> = Vec::new();
     let mut rdr = csv::ReaderBuilder::new()
         .delimiter(b'\t')
         .flexible(true)
         .from_reader(ga_data.as_bytes());
-    for result in rdr.deserialize() {
-        let record: GlobalAttributeCV = result?;
+    for (index, result) in rdr.deserialize().enumerate() {
+        let record: GlobalAttributeCV =
+            result.map_err(|err| NcasCdlError::malformed_csv(&file_path, err))?;
         let attr = match record.name {
             Some(name) => GlobalAttribute {
                 name,
                 value: record.fixed_value.unwrap_or_default(),
                 example: record.example.unwrap_or_default(),
                 compliance: record.compliance_checking_rules.unwrap_or_default(),
+                // Only the common attributes have regex checks
+                regex_check: None,
             },
             None => {
-                return Err(format!(
-                    "Can not find name for global attribute in data product {}",
-                    data_product
-                )
-                .into());
+                return Err(NcasCdlError::MalformedCv {
+                    file: file_path,
+                    line: index as u64 + 2,
+                    reason: format!(
+                        "Can not find name for global attribute in data product {}",
+                        data_product
+                    ),
+                });
             }
         };
         attrs.push(attr);
@@ -94,16 +113,18 @@ async fn get_data_product_global_attributes(
 }
 
 async fn get_data_product_variables(
+    source: &dyn CvSource,
     data_product: &str,
     tag: &str,
-) -> Result<Vec<Variable>, Box<dyn Error>> {
-    let file_path = "https://raw.githubusercontent.com/ncasuk/AMF_CVs/".to_owned()
-        + tag
-        + "/product-definitions/tsv/"
-        + data_product
-        + "/variables-specific.tsv";
-    let res = reqwest::get(&file_path).await?;
-    let v_data = res.text().await?;
+) -> Result<Vec<Variable>, NcasCdlError> {
+    let file_path = format!(
+        "product-definitions/tsv/{}/variables-specific.tsv",
+        data_product
+    );
+    let v_data = source
+        .fetch(CvRepository::AmfCvs, tag, &file_path)
+        .await
+        .map_err(|err| unknown_data_product(err, data_product, tag))?;
     // Skip the first line (header)
     let mut lines = v_data.lines();
     lines.next(); // Discard the first line
@@ -117,7 +138,7 @@ async fn get_data_product_variables(
     for block in blocks {
         let lines = block.lines();
         let mut variable_name = String::new();
-        let mut attributes = HashMap::new();
+        let mut attributes = IndexMap::new();
 
         for line in lines {
             let parts: Vec<&str> = line.split('\t').collect();
@@ -145,6 +166,9 @@ async fn get_data_product_variables(
             variables.push(Variable {
                 name: variable_name,
                 attributes,
+                // The data product CVs have no rules for variable attributes
+                rules: IndexMap::new(),
+                source: file_path.clone(),
             });
         }
     }
@@ -169,34 +193,40 @@ fn reformat_flag_meanings(flag_meanings: &str) -> String {
 }
 
 async fn get_data_product_dimensions(
+    source: &dyn CvSource,
     data_product: &str,
     tag: &str,
-) -> Result<Vec<Dimension>, Box<dyn Error>> {
-    let file_path = "https://raw.githubusercontent.com/ncasuk/AMF_CVs/".to_owned()
-        + tag
-        + "/product-definitions/tsv/"
-        + data_product
-        + "/dimensions-specific.tsv";
-    let res = reqwest::get(&file_path).await?;
-    let dp_data = res.text().await?;
+) -> Result<Vec<Dimension>, NcasCdlError> {
+    let file_path = format!(
+        "product-definitions/tsv/{}/dimensions-specific.tsv",
+        data_product
+    );
+    let dp_data = source
+        .fetch(CvRepository::AmfCvs, tag, &file_path)
+        .await
+        .map_err(|err| unknown_data_product(err, data_product, tag))?;
     let mut dimensions: Vec<Dimension> = Vec::new();
     let mut rdr = csv::ReaderBuilder::new()
         .delimiter(b'\t')
         .flexible(true)
         .from_reader(dp_data.as_bytes());
-    for result in rdr.deserialize() {
-        let record: DimensionCV = result?;
+    for (index, result) in rdr.deserialize().enumerate() {
+        let record: DimensionCV =
+            result.map_err(|err| NcasCdlError::malformed_csv(&file_path, err))?;
         let dimension = match record.name {
             Some(name) => Dimension {
                 name,
                 length: record.length,
             },
             None => {
-                return Err(format!(
-                    "Can not find name for dimension in data product {}",
-                    data_product
-                )
-                .into());
+                return Err(NcasCdlError::MalformedCv {
+                    file: file_path,
+                    line: index as u64 + 2,
+                    reason: format!(
+                        "Can not find name for dimension in data product {}",
+                        data_product
+                    ),
+                });
             }
         };
         dimensions.push(dimension);
@@ -205,12 +235,15 @@ async fn get_data_product_dimensions(
 }
 
 pub async fn get_data_product(
+    source: &dyn CvSource,
     data_product: String,
     tag: String,
-) -> Result<DataProduct, Box<dyn Error>> {
-    let variables = get_data_product_variables(&data_product, &tag).await?;
-    let dimensions = get_data_product_dimensions(&data_product, &tag).await?;
-    let global_attributes = get_data_product_global_attributes(&data_product, &tag).await?;
+) -> Result<DataProduct, NcasCdlError> {
+    let (variables, dimensions, global_attributes) = tokio::try_join!(
+        get_data_product_variables(source, &data_product, &tag),
+        get_data_product_dimensions(source, &data_product, &tag),
+        get_data_product_global_attributes(source, &data_product, &tag),
+    )?;
     let data_product = DataProduct {
         name: data_product,
         variables,
@@ -219,3 +252,115 @@ pub async fn get_data_product(
     };
     Ok(data_product)
 }
+
+#[derive(Debug, Serialize)]
+pub struct DataProductSummary {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+// Descriptions of each data product, where the tag has a vocabulary listing them
+async fn get_data_product_descriptions(
+    source: &dyn CvSource,
+    tag: &str,
+) -> Result<HashMap<String, String>, NcasCdlError> {
+    let file_path = "product-definitions/tsv/_vocabularies/data-products.tsv";
+    let dp_data = match source.fetch(CvRepository::AmfCvs, tag, file_path).await {
+        Ok(dp_data) => dp_data,
+        Err(NcasCdlError::CvFileNotFound { .. }) => return Ok(HashMap::new()),
+        Err(err) => return Err(err),
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_reader(dp_data.as_bytes());
+    let headers = rdr
+        .headers()
+        .map_err(|err| NcasCdlError::malformed_csv(file_path, err))?
+        .clone();
+    let description_column = headers
+        .iter()
+        .position(|header| header.to_lowercase().contains("description"));
+    let mut descriptions = HashMap::new();
+    for result in rdr.records() {
+        let record = result.map_err(|err| NcasCdlError::malformed_csv(file_path, err))?;
+        if let (Some(name), Some(description)) = (
+            record.get(0),
+            description_column.and_then(|column| record.get(column)),
+        ) {
+            descriptions.insert(name.to_string(), description.to_string());
+        }
+    }
+    Ok(descriptions)
+}
+
+/// Lists the data products defined at a tag, i.e. the directories under
+/// `product-definitions/tsv/` other than those starting with an underscore such as `_common`.
+pub async fn list_data_products(
+    source: &dyn CvSource,
+    tag: String,
+) -> Result<Vec<DataProductSummary>, NcasCdlError> {
+    let (entries, descriptions) = tokio::join!(
+        source.list(CvRepository::AmfCvs, &tag, "product-definitions/tsv"),
+        get_data_product_descriptions(source, &tag),
+    );
+    let entries = entries.map_err(|err| {
+        err.when_not_found(|location| NcasCdlError::UnknownTag {
+            tag: tag.clone(),
+            location,
+        })
+    })?;
+    let mut descriptions = descriptions?;
+    let mut data_products: Vec<DataProductSummary> = entries
+        .into_iter()
+        .filter(|entry| entry.is_dir && !entry.name.starts_with('_'))
+        .map(|entry| DataProductSummary {
+            description: descriptions.remove(&entry.name),
+            name: entry.name,
+        })
+        .collect();
+    data_products.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(data_products)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::test_fixtures;
+
+    #[tokio::test]
+    async fn data_products_are_the_directories_not_starting_with_an_underscore() {
+        let source = test_fixtures::cv_source();
+        let data_products = list_data_products(&source, test_fixtures::TAG.to_string())
+            .await
+            .unwrap();
+        let listed: Vec<(&str, Option<&str>)> = data_products
+            .iter()
+            .map(|product| (product.name.as_str(), product.description.as_deref()))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (
+                    "aerosol-backscatter",
+                    Some("Attenuated aerosol backscatter profiles")
+                ),
+                ("surface-met", None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_tags_and_data_products_are_reported() {
+        let source = test_fixtures::cv_source();
+        assert!(matches!(
+            list_data_products(&source, "v9.9.9".to_string()).await,
+            Err(NcasCdlError::UnknownTag { tag, .. }) if tag == "v9.9.9"
+        ));
+        assert!(matches!(
+            get_data_product(&source, "sea-ice".to_string(), test_fixtures::TAG.to_string())
+                .await,
+            Err(NcasCdlError::UnknownDataProduct { name, .. }) if name == "sea-ice"
+        ));
+    }
+}
@@ -1,7 +1,9 @@
+use super::cv_source::{CvRepository, CvSource};
+use super::error::NcasCdlError;
 use super::netcdf_components::{Dimension, Variable};
+use indexmap::IndexMap;
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::error::Error;
+use tracing::warn;
 
 #[derive(Debug, Deserialize)]
 pub struct Deployment {
@@ -35,17 +37,63 @@ pub struct VariableCV {
     pub compliance: Option<String>,
 }
 
+// A deployment mode that doesn't exist at this tag shows up as a missing file
+fn unknown_deployment_mode(err: NcasCdlError, deployment: &str, tag: &str) -> NcasCdlError {
+    err.when_not_found(|location| NcasCdlError::UnknownDeploymentMode {
+        mode: deployment.to_string(),
+        tag: tag.to_string(),
+        location,
+        valid_modes: Vec::new(),
+    })
+}
+
+/// Lists the deployment modes defined at a tag, i.e. those with both a
+/// `variables-<mode>.tsv` and a `dimensions-<mode>.tsv` file in `_common`.
+pub async fn list_deployment_modes(
+    source: &dyn CvSource,
+    tag: String,
+) -> Result<Vec<String>, NcasCdlError> {
+    let entries = source
+        .list(
+            CvRepository::AmfCvs,
+            &tag,
+            "product-definitions/tsv/_common",
+        )
+        .await
+        .map_err(|err| {
+            err.when_not_found(|location| NcasCdlError::UnknownTag {
+                tag: tag.clone(),
+                location,
+            })
+        })?;
+    let file_names: Vec<&str> = entries
+        .iter()
+        .filter(|entry| !entry.is_dir)
+        .map(|entry| entry.name.as_str())
+        .collect();
+    let mut modes: Vec<String> = file_names
+        .iter()
+        .filter_map(|name| name.strip_prefix("variables-")?.strip_suffix(".tsv"))
+        .filter(|mode| file_names.contains(&format!("dimensions-{}.tsv", mode).as_str()))
+        .map(|mode| mode.to_string())
+        .collect();
+    modes.sort();
+    Ok(modes)
+}
+
 async fn get_deployment_variables(
+    source: &dyn CvSource,
     deployment: &str,
     tag: &str,
-) -> Result<Vec<Variable>, Box<dyn Error>> {
-    let file_path = "https://raw.githubusercontent.com/ncasuk/AMF_CVs/".to_owned()
-        + tag
-        + "/product-definitions/tsv/_common/variables-"
-        + deployment
-        + ".tsv";
-    let res = reqwest::get(&file_path).await?;
-    let v_data = res.text().await?;
+) -> Result<Vec<Variable>, NcasCdlError> {
+    let file_path = format!(
+        "product-definitions/tsv/_common/variables-{}.tsv",
+        deployment
+    );
+    let v_data = source
+        .fetch(CvRepository::AmfCvs, tag, &file_path)
+        .await
+        .map_err(|err| unknown_deployment_mode(err, deployment, tag))?;
     // Skip the first line (header)
     let mut lines = v_data.lines();
     lines.next(); // Discard the first line
@@ -59,7 +107,8 @@ async fn get_deployment_variables(
     for block in blocks {
         let lines = block.lines();
         let mut variable_name = String::new();
-        let mut attributes = HashMap::new();
+        let mut attributes = IndexMap::new();
+        let mut rules = IndexMap::new();
 
         for line in lines {
             let parts: Vec<&str> = line.split('\t').collect();
@@ -80,6 +129,9 @@ async fn get_deployment_variables(
                 } else {
                     attr_value
                 };
+                if let Some(rule) = parts.get(3).filter(|rule| !rule.is_empty()) {
+                    rules.insert(attr_name.clone(), rule.to_string());
+                }
                 attributes.insert(attr_name, attr_value);
             }
         }
@@ -88,6 +140,8 @@ async fn get_deployment_variables(
             variables.push(Variable {
                 name: variable_name,
                 attributes,
+                rules,
+                source: file_path.clone(),
             });
         }
     }
@@ -112,34 +166,40 @@ fn reformat_flag_meanings(flag_meanings: &str) -> String {
 }
 
 async fn get_deployment_dimensions(
+    source: &dyn CvSource,
     deployment: &str,
     tag: &str,
-) -> Result<Vec<Dimension>, Box<dyn Error>> {
-    let file_path = "https://raw.githubusercontent.com/ncasuk/AMF_CVs/".to_owned()
-        + tag
-        + "/product-definitions/tsv/_common/dimensions-"
-        + deployment
-        + ".tsv";
-    let res = reqwest::get(&file_path).await?;
-    let dp_data = res.text().await?;
+) -> Result<Vec<Dimension>, NcasCdlError> {
+    let file_path = format!(
+        "product-definitions/tsv/_common/dimensions-{}.tsv",
+        deployment
+    );
+    let dp_data = source
+        .fetch(CvRepository::AmfCvs, tag, &file_path)
+        .await
+        .map_err(|err| unknown_deployment_mode(err, deployment, tag))?;
     let mut dimensions: Vec<Dimension> = Vec::new();
     let mut rdr = csv::ReaderBuilder::new()
         .delimiter(b'\t')
         .flexible(true)
         .from_reader(dp_data.as_bytes());
-    for result in rdr.deserialize() {
-        let record: DimensionCV = result?;
+    for (index, result) in rdr.deserialize().enumerate() {
+        let record: DimensionCV =
+            result.map_err(|err| NcasCdlError::malformed_csv(&file_path, err))?;
         let dimension = match record.name {
             Some(name) => Dimension {
                 name,
                 length: record.length,
             },
             None => {
-                return Err(format!(
-                    "Can not find name for dimension in deployment {}",
-                    deployment
-                )
-                .into());
+                return Err(NcasCdlError::MalformedCv {
+                    file: file_path,
+                    line: index as u64 + 2,
+                    reason: format!(
+                        "Can not find name for dimension in deployment {}",
+                        deployment
+                    ),
+                });
             }
         };
         dimensions.push(dimension);
@@ -147,9 +207,36 @@ async fn get_deployment_dimensions(
     Ok(dimensions)
 }
 
-pub async fn get_deployment(deployment: String, tag: String) -> Result<Deployment, Box<dyn Error>> {
-    let variables = get_deployment_variables(&deployment, &tag).await?;
-    let dimensions = get_deployment_dimensions(&deployment, &tag).await?;
+pub async fn get_deployment(
+    source: &dyn CvSource,
+    deployment: String,
+    tag: String,
+) -> Result<Deployment, NcasCdlError> {
+    let fetched = tokio::try_join!(
+        get_deployment_variables(source, &deployment, &tag),
+        get_deployment_dimensions(source, &deployment, &tag),
+    );
+    let (variables, dimensions) = match fetched {
+        Ok(fetched) => fetched,
+        // Only list the modes when the requested one is missing, to say which do exist
+        Err(NcasCdlError::UnknownDeploymentMode { location, .. }) => {
+            return Err(match list_deployment_modes(source, tag.clone()).await {
+                Err(err @ NcasCdlError::UnknownTag { .. }) => err,
+                listed => {
+                    let valid_modes = listed
+                        .inspect_err(|err| warn!("Could not list deployment modes: {}", err))
+                        .unwrap_or_default();
+                    NcasCdlError::UnknownDeploymentMode {
+                        mode: deployment,
+                        tag,
+                        location,
+                        valid_modes,
+                    }
+                }
+            });
+        }
+        Err(err) => return Err(err),
+    };
     let deployment = Deployment {
         name: deployment,
         variables,
@@ -157,3 +244,60 @@ pub async fn get_deployment(deployment: String, tag: String) -> Result<Deploymen
     };
     Ok(deployment)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::test_fixtures;
+
+    #[tokio::test]
+    async fn modes_need_both_variables_and_dimensions() {
+        let source = test_fixtures::cv_source();
+        let modes = list_deployment_modes(&source, test_fixtures::TAG.to_string())
+            .await
+            .unwrap();
+        assert_eq!(modes, ["land"]);
+        let deployment = get_deployment(&source, "land".to_string(), test_fixtures::TAG.into())
+            .await
+            .unwrap();
+        let names: Vec<&str> = deployment
+            .variables
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect();
+        assert_eq!(names, ["time", "latitude"]);
+        assert_eq!(deployment.dimensions.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_modes_list_the_valid_ones() {
+        let source = test_fixtures::cv_source();
+        for mode in ["sea", "air"] {
+            let err = get_deployment(&source, mode.to_string(), test_fixtures::TAG.into())
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(
+                    &err,
+                    NcasCdlError::UnknownDeploymentMode { mode: unknown, valid_modes, .. }
+                        if unknown == mode && valid_modes == &["land"]
+                ),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_tags_are_reported() {
+        let source = test_fixtures::cv_source();
+        assert!(matches!(
+            list_deployment_modes(&source, "v9.9.9".to_string()).await,
+            Err(NcasCdlError::UnknownTag { .. })
+        ));
+        assert!(matches!(
+            get_deployment(&source, "land".to_string(), "v9.9.9".to_string()).await,
+            Err(NcasCdlError::UnknownTag { .. })
+        ));
+    }
+}
@@ -0,0 +1,300 @@
+use axum::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum NcasCdlError {
+    /// A required query parameter was missing or empty.
+    MissingParameter(String),
+    InvalidParameter {
+        name: String,
+        reason: String,
+    },
+    UnknownInstrument {
+        name: String,
+        /// Registered instruments with similar names.
+        suggestions: Vec<String>,
+    },
+    UnknownDataProduct {
+        name: String,
+        tag: String,
+        location: String,
+    },
+    UnknownDeploymentMode {
+        mode: String,
+        tag: String,
+        location: String,
+        /// Modes that do exist at the tag, when they could be listed.
+        valid_modes: Vec<String>,
+    },
+    UnknownTag {
+        tag: String,
+        location: String,
+    },
+    /// The instrument is not registered as producing the data product.
+    IncompatibleDataProduct {
+        instrument: String,
+        data_product: String,
+        registered: Vec<String>,
+    },
+    /// A CV file does not exist. Loaders replace this with the Unknown* variant for
+    /// whatever the missing file was meant to describe.
+    CvFileNotFound {
+        location: String,
+    },
+    /// A CV file could not be fetched, e.g. because GitHub could not be reached.
+    UpstreamUnavailable {
+        location: String,
+        reason: String,
+    },
+    /// A variable in the CVs has a type that NetCDF doesn't have.
+    UnknownDataType {
+        variable: String,
+        data_type: String,
+        file: String,
+    },
+    /// A CV file was fetched but could not be parsed.
+    MalformedCv {
+        file: String,
+        line: u64,
+        reason: String,
+    },
+    /// The template could not be written in the requested format.
+    OutputFailed {
+        format: String,
+        reason: String,
+    },
+    /// CDL sent to be checked could not be parsed.
+    InvalidCdl {
+        line: usize,
+        reason: String,
+    },
+    /// A NetCDF file with the requested dimension lengths would be too big to send.
+    OutputTooLarge {
+        bytes: u64,
+        limit: u64,
+    },
+    /// A request body that isn't what the endpoint expects.
+    InvalidBody(String),
+    /// Values given for global attributes that don't follow the standard.
+    InvalidAttributeValues(Vec<String>),
+    UnknownProfile(String),
+    /// The file profiles are saved in could not be read or written.
+    ProfileStoreFailed {
+        location: String,
+        reason: String,
+    },
+}
+
+impl NcasCdlError {
+    /// Stable identifier for the kind of error, for clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            NcasCdlError::MissingParameter(_) => "missing_parameter",
+            NcasCdlError::InvalidParameter { .. } => "invalid_parameter",
+            NcasCdlError::UnknownInstrument { .. } => "unknown_instrument",
+            NcasCdlError::UnknownDataProduct { .. } => "unknown_data_product",
+            NcasCdlError::UnknownDeploymentMode { .. } => "unknown_deployment_mode",
+            NcasCdlError::UnknownTag { .. } => "unknown_tag",
+            NcasCdlError::IncompatibleDataProduct { .. } => "incompatible_data_product",
+            NcasCdlError::CvFileNotFound { .. } => "cv_file_not_found",
+            NcasCdlError::UpstreamUnavailable { .. } => "upstream_unavailable",
+            NcasCdlError::UnknownDataType { .. } => "unknown_data_type",
+            NcasCdlError::MalformedCv { .. } => "malformed_cv",
+            NcasCdlError::OutputFailed { .. } => "output_failed",
+            NcasCdlError::InvalidCdl { .. } => "invalid_cdl",
+            NcasCdlError::OutputTooLarge { .. } => "output_too_large",
+            NcasCdlError::InvalidBody(_) => "invalid_body",
+            NcasCdlError::InvalidAttributeValues(_) => "invalid_attribute_values",
+            NcasCdlError::UnknownProfile(_) => "unknown_profile",
+            NcasCdlError::ProfileStoreFailed { .. } => "profile_store_failed",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            NcasCdlError::MissingParameter(_)
+            | NcasCdlError::InvalidParameter { .. }
+            | NcasCdlError::IncompatibleDataProduct { .. }
+            | NcasCdlError::InvalidCdl { .. }
+            | NcasCdlError::OutputTooLarge { .. }
+            | NcasCdlError::InvalidBody(_)
+            | NcasCdlError::InvalidAttributeValues(_) => StatusCode::BAD_REQUEST,
+            NcasCdlError::UnknownInstrument { .. }
+            | NcasCdlError::UnknownDataProduct { .. }
+            | NcasCdlError::UnknownDeploymentMode { .. }
+            | NcasCdlError::UnknownTag { .. }
+            | NcasCdlError::UnknownProfile(_) => StatusCode::NOT_FOUND,
+            NcasCdlError::CvFileNotFound { .. }
+            | NcasCdlError::UpstreamUnavailable { .. }
+            | NcasCdlError::UnknownDataType { .. }
+            | NcasCdlError::MalformedCv { .. } => StatusCode::BAD_GATEWAY,
+            NcasCdlError::OutputFailed { .. } | NcasCdlError::ProfileStoreFailed { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Replaces a missing CV file error with one saying what the caller was looking for.
+    pub fn when_not_found(self, unknown: impl FnOnce(String) -> NcasCdlError) -> Self {
+        match self {
+            NcasCdlError::CvFileNotFound { location } => unknown(location),
+            err => err,
+        }
+    }
+
+    pub fn malformed_csv(file: &str, err: csv::Error) -> Self {
+        NcasCdlError::MalformedCv {
+            file: file.to_string(),
+            line: err.position().map_or(0, |pos| pos.line()),
+            reason: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for NcasCdlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NcasCdlError::MissingParameter(name) => write!(f, "{} parameter is required", name),
+            NcasCdlError::InvalidParameter { name, reason } => {
+                write!(f, "{} parameter is invalid: {}", name, reason)
+            }
+            NcasCdlError::UnknownInstrument { name, suggestions } => {
+                write!(
+                    f,
+                    "Instrument {} is not in the NCAS instrument vocabulary",
+                    name
+                )?;
+                if !suggestions.is_empty() {
+                    write!(f, "; did you mean {}?", suggestions.join(", "))?;
+                }
+                Ok(())
+            }
+            NcasCdlError::UnknownDataProduct {
+                name,
+                tag,
+                location,
+            } => write!(
+                f,
+                "Data product {} does not exist in AMF_CVs {} ({} not found)",
+                name, tag, location
+            ),
+            NcasCdlError::UnknownDeploymentMode {
+                mode,
+                tag,
+                location,
+                valid_modes,
+            } => {
+                write!(
+                    f,
+                    "Deployment mode {} does not exist in AMF_CVs {} ({} not found)",
+                    mode, tag, location
+                )?;
+                if !valid_modes.is_empty() {
+                    write!(f, "; valid modes are {}", valid_modes.join(", "))?;
+                }
+                Ok(())
+            }
+            NcasCdlError::UnknownTag { tag, location } => {
+                write!(f, "AMF_CVs has no tag {} ({} not found)", tag, location)
+            }
+            NcasCdlError::IncompatibleDataProduct {
+                instrument,
+                data_product,
+                registered,
+            } => write!(
+                f,
+                "Instrument {} is not registered for data product {}; it is registered for {}",
+                instrument,
+                data_product,
+                registered.join(", ")
+            ),
+            NcasCdlError::CvFileNotFound { location } => write!(f, "{} not found", location),
+            NcasCdlError::UpstreamUnavailable { location, reason } => {
+                write!(f, "Could not fetch {}: {}", location, reason)
+            }
+            NcasCdlError::UnknownDataType {
+                variable,
+                data_type,
+                file,
+            } if data_type.is_empty() => {
+                write!(f, "Variable {} in {} has no type", variable, file)
+            }
+            NcasCdlError::UnknownDataType {
+                variable,
+                data_type,
+                file,
+            } => write!(
+                f,
+                "Variable {} in {} has type {}, which is not a NetCDF type",
+                variable, file, data_type
+            ),
+            NcasCdlError::MalformedCv { file, line, reason } => {
+                write!(f, "Could not parse {} at line {}: {}", file, line, reason)
+            }
+            NcasCdlError::OutputFailed { format, reason } => {
+                write!(f, "Could not write the template as {}: {}", format, reason)
+            }
+            NcasCdlError::InvalidCdl { line, reason } => {
+                write!(f, "Could not parse the CDL at line {}: {}", line, reason)
+            }
+            NcasCdlError::OutputTooLarge { bytes, limit } => write!(
+                f,
+                "The NetCDF file would be {} bytes, which is more than the limit of {} bytes",
+                bytes, limit
+            ),
+            NcasCdlError::InvalidBody(reason) => {
+                write!(f, "The request body is invalid: {}", reason)
+            }
+            NcasCdlError::InvalidAttributeValues(problems) => write!(
+                f,
+                "Global attribute values do not follow the standard: {}",
+                problems.join("; ")
+            ),
+            NcasCdlError::UnknownProfile(name) => write!(f, "Profile {} does not exist", name),
+            NcasCdlError::ProfileStoreFailed { location, reason } => {
+                write!(
+                    f,
+                    "Could not save or load profiles in {}: {}",
+                    location, reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for NcasCdlError {}
+
+impl IntoResponse for NcasCdlError {
+    fn into_response(self) -> Response {
+        let mut body = json!({
+            "error": self.to_string(),
+            "code": self.code(),
+        });
+        // Give clients what they need to correct the request without parsing the message
+        match &self {
+            NcasCdlError::UnknownInstrument { suggestions, .. } => {
+                body["suggestions"] = json!(suggestions);
+            }
+            NcasCdlError::UnknownDeploymentMode { valid_modes, .. } => {
+                body["valid_modes"] = json!(valid_modes);
+            }
+            NcasCdlError::IncompatibleDataProduct { registered, .. } => {
+                body["registered_data_products"] = json!(registered);
+            }
+            NcasCdlError::InvalidCdl { line, .. } => {
+                body["line"] = json!(line);
+            }
+            NcasCdlError::OutputTooLarge { limit, .. } => {
+                body["limit"] = json!(limit);
+            }
+            NcasCdlError::InvalidAttributeValues(problems) => {
+                body["problems"] = json!(problems);
+            }
+            _ => {}
+        }
+        (self.status(), Json(body)).into_response()
+    }
+}
@@ -1,5 +1,6 @@
-use serde::Deserialize;
-use std::error::Error;
+use super::cv_source::{CvRepository, CvSource};
+use super::error::NcasCdlError;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
 pub struct InstRecord {
@@ -42,16 +43,296 @@ pub struct InstRecord {
     pub pid: Option<String>,
 }
 
-pub async fn get_instrument_data(instrument_name: String) -> Result<InstRecord, Box<dyn Error>> {
-    let instrument_name = Some(instrument_name);
-    let file_path = "https://raw.githubusercontent.com/ncasuk/ncas-data-instrument-vocabs/refs/heads/main/product-definitions/tsv/_instrument_vocabs/ncas-instrument-name-and-descriptors.tsv";
-    let mut instrument_record = InstRecord {
+const INSTRUMENT_VOCAB_REF: &str = "refs/heads/main";
+const INSTRUMENT_VOCAB_PATH: &str =
+    "product-definitions/tsv/_instrument_vocabs/ncas-instrument-name-and-descriptors.tsv";
+
+impl InstRecord {
+    /// The data products listed in the "Data Product(s)" column.
+    pub fn data_products(&self) -> Vec<String> {
+        self.data_product
+            .as_deref()
+            .unwrap_or_default()
+            .split([',', ';', '\n'])
+            .map(|product| product.trim().to_string())
+            .filter(|product| !product.is_empty())
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstrumentSummary {
+    pub name: String,
+    pub old_name: Option<String>,
+    pub descriptor: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub category: Option<String>,
+    pub owner: Option<String>,
+    pub data_products: Vec<String>,
+    pub mobile_fixed: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct InstrumentQuery {
+    /// Case-insensitive text to find in the instrument's names, descriptor, manufacturer or model.
+    pub q: Option<String>,
+    pub category: Option<String>,
+    pub owner: Option<String>,
+    /// Starts at 1.
+    pub page: usize,
+    pub per_page: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstrumentPage {
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+    pub instruments: Vec<InstrumentSummary>,
+}
+
+fn contains_ignore_case(field: &Option<String>, text: &str) -> bool {
+    field
+        .as_ref()
+        .is_some_and(|value| value.to_lowercase().contains(text))
+}
+
+fn equals_ignore_case(field: &Option<String>, text: &str) -> bool {
+    field
+        .as_ref()
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case(text.trim()))
+}
+
+impl InstrumentQuery {
+    fn matches(&self, record: &InstRecord) -> bool {
+        let matches_q = self.q.as_ref().is_none_or(|q| {
+            let q = q.to_lowercase();
+            [
+                &record.instrument_name,
+                &record.old_instrument_name,
+                &record.descriptor,
+                &record.instrument,
+                &record.manufacturer,
+                &record.model_no,
+            ]
+            .iter()
+            .any(|field| contains_ignore_case(field, &q))
+        });
+        matches_q
+            && self
+                .category
+                .as_ref()
+                .is_none_or(|category| equals_ignore_case(&record.category, category))
+            && self
+                .owner
+                .as_ref()
+                .is_none_or(|owner| equals_ignore_case(&record.owner, owner))
+    }
+}
+
+pub async fn get_instrument_records(
+    source: &dyn CvSource,
+) -> Result<Vec<InstRecord>, NcasCdlError> {
+    let inst_data = source
+        .fetch(
+            CvRepository::InstrumentVocabs,
+            INSTRUMENT_VOCAB_REF,
+            INSTRUMENT_VOCAB_PATH,
+        )
+        .await?;
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_reader(inst_data.as_bytes());
+    let mut records = Vec::new();
+    for result in rdr.deserialize() {
+        let record: InstRecord =
+            result.map_err(|err| NcasCdlError::malformed_csv(INSTRUMENT_VOCAB_PATH, err))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+pub async fn search_instruments(
+    source: &dyn CvSource,
+    query: InstrumentQuery,
+) -> Result<InstrumentPage, NcasCdlError> {
+    let records = get_instrument_records(source).await?;
+    let matches: Vec<InstRecord> = records
+        .into_iter()
+        .filter(|record| record.instrument_name.is_some() && query.matches(record))
+        .collect();
+    let total = matches.len();
+    let instruments = matches
+        .into_iter()
+        .skip((query.page - 1).saturating_mul(query.per_page))
+        .take(query.per_page)
+        .map(|record| InstrumentSummary {
+            data_products: record.data_products(),
+            name: record.instrument_name.unwrap_or_default(),
+            old_name: record.old_instrument_name,
+            descriptor: record.descriptor,
+            manufacturer: record.manufacturer,
+            model: record.model_no,
+            category: record.category,
+            owner: record.owner,
+            mobile_fixed: record.mobile_fixed,
+        })
+        .collect();
+    Ok(InstrumentPage {
+        total,
+        page: query.page,
+        per_page: query.per_page,
+        instruments,
+    })
+}
+
+// Number of single character insertions, deletions or substitutions to turn one string
+// into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Current names of the instruments whose current or old name is closest to `name`.
+fn closest_instruments(records: &[InstRecord], name: &str) -> Vec<String> {
+    const MAX_SUGGESTIONS: usize = 5;
+    let max_distance = (name.chars().count() / 2).max(2);
+    let name = name.to_lowercase();
+    let mut candidates: Vec<(usize, &String)> = records
+        .iter()
+        .filter_map(|record| {
+            let current_name = record.instrument_name.as_ref()?;
+            let distance = std::iter::once(current_name.as_str())
+                .chain(record.old_names())
+                .map(|candidate| edit_distance(&name, &candidate.to_lowercase()))
+                .min()?;
+            (distance <= max_distance).then_some((distance, current_name))
+        })
+        .collect();
+    candidates.sort();
+    let mut suggestions: Vec<String> = Vec::new();
+    for (_, candidate) in candidates {
+        if !suggestions.contains(candidate) {
+            suggestions.push(candidate.clone());
+        }
+    }
+    suggestions.truncate(MAX_SUGGESTIONS);
+    suggestions
+}
+
+impl InstRecord {
+    /// The names the instrument had before being renamed. The column can list several,
+    /// separated by commas.
+    fn old_names(&self) -> impl Iterator<Item = &str> {
+        self.old_instrument_name
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|old_name| !old_name.is_empty())
+    }
+
+    fn has_old_name(&self, name: &str) -> bool {
+        self.old_names().any(|old_name| old_name == name)
+    }
+}
+
+/// Checks the instrument is registered for the data product. A mismatch is an error when
+/// `strict`, otherwise it is returned as a warning. Instruments with no data products
+/// listed, such as unregistered ones, can't be checked and are let through.
+pub fn check_data_product(
+    record: &InstRecord,
+    data_product: &str,
+    strict: bool,
+) -> Result<Option<String>, NcasCdlError> {
+    let registered = record.data_products();
+    if registered.is_empty() || registered.iter().any(|product| product == data_product) {
+        return Ok(None);
+    }
+    let err = NcasCdlError::IncompatibleDataProduct {
+        instrument: record.instrument_name.clone().unwrap_or_default(),
+        data_product: data_product.to_string(),
+        registered,
+    };
+    if strict {
+        Err(err)
+    } else {
+        Ok(Some(err.to_string()))
+    }
+}
+
+// An unregistered name goes into file names, CDL and headers as it is, so it has to
+// look like the names in the vocabulary
+fn is_valid_instrument_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+/// Finds the instrument in the vocabulary, along with warnings about how it was found.
+///
+/// Names from before an instrument was renamed are resolved to the current record.
+/// Instruments that have not been registered yet are only accepted with
+/// `allow_unregistered`, in which case a record with nothing but the name is returned.
+pub async fn get_instrument_data(
+    source: &dyn CvSource,
+    instrument_name: String,
+    allow_unregistered: bool,
+) -> Result<(InstRecord, Vec<String>), NcasCdlError> {
+    let mut records = get_instrument_records(source).await?;
+    if let Some(index) = records
+        .iter()
+        .position(|record| record.instrument_name.as_ref() == Some(&instrument_name))
+    {
+        return Ok((records.swap_remove(index), Vec::new()));
+    }
+    if let Some(index) = records
+        .iter()
+        .position(|record| record.has_old_name(&instrument_name))
+    {
+        let record = records.swap_remove(index);
+        let new_name = record.instrument_name.as_deref().unwrap_or("unknown");
+        let warning = format!(
+            "Instrument {} has been renamed to {}; using {}",
+            instrument_name, new_name, new_name
+        );
+        return Ok((record, vec![warning]));
+    }
+    if !allow_unregistered {
+        return Err(NcasCdlError::UnknownInstrument {
+            suggestions: closest_instruments(&records, &instrument_name),
+            name: instrument_name,
+        });
+    }
+    if !is_valid_instrument_name(&instrument_name) {
+        return Err(NcasCdlError::InvalidParameter {
+            name: "instrument".to_string(),
+            reason: format!(
+                "unregistered instrument names may only use letters, digits, dashes and \
+                 underscores, got {:?}",
+                instrument_name
+            ),
+        });
+    }
+    let record = InstRecord {
         instrument: None,
         manufacturer: None,
         model_no: None,
         serial_number: None,
         old_instrument_name: None,
-        instrument_name: instrument_name.clone(),
+        instrument_name: Some(instrument_name),
         data_product: None,
         mobile_fixed: None,
         host: None,
@@ -61,17 +342,196 @@ pub async fn get_instrument_data(instrument_name: String) -> Result<InstRecord,
         owner: None,
         pid: None,
     };
-    let res = reqwest::get(file_path).await?;
-    let inst_data = res.text().await?;
-    let mut rdr = csv::ReaderBuilder::new()
-        .delimiter(b'\t')
-        .flexible(true)
-        .from_reader(inst_data.as_bytes());
-    for result in rdr.deserialize() {
-        let record: InstRecord = result?;
-        if record.instrument_name == instrument_name {
-            instrument_record = record;
+    let warning = format!(
+        "Instrument {} is not in the NCAS instrument vocabulary",
+        record.instrument_name.as_deref().unwrap_or("unknown")
+    );
+    Ok((record, vec![warning]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::test_fixtures;
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("ceilometer", "ceilometer"), 0);
+        assert_eq!(edit_distance("ceilometr", "ceilometer"), 1);
+        assert_eq!(edit_distance("ceilometer", "ceilomater"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        // Characters, not bytes
+        assert_eq!(edit_distance("µm", "um"), 1);
+    }
+
+    #[tokio::test]
+    async fn suggestions_are_the_closest_current_names() {
+        let records = get_instrument_records(&test_fixtures::cv_source())
+            .await
+            .unwrap();
+        assert_eq!(
+            closest_instruments(&records, "ncas-ceilometr-3"),
+            ["ncas-ceilometer-3"]
+        );
+        // Case doesn't matter, and old names lead to the current one
+        assert_eq!(
+            closest_instruments(&records, "NCAS-Ceilometer-2a"),
+            ["ncas-ceilometer-3"]
+        );
+        assert_eq!(
+            closest_instruments(&records, "ncas-lidar-dop"),
+            ["ncas-lidar-dop-2"]
+        );
+        // Any of several old names
+        assert_eq!(
+            closest_instruments(&records, "ncas-dopler-lidar-1"),
+            ["ncas-lidar-dop-2"]
+        );
+        assert!(closest_instruments(&records, "weather-station").is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_instruments_are_rejected_with_suggestions() {
+        let source = test_fixtures::cv_source();
+        let err = get_instrument_data(&source, "ncas-ceilometr-3".to_string(), false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            NcasCdlError::UnknownInstrument { suggestions, .. }
+                if suggestions == ["ncas-ceilometer-3"]
+        ));
+
+        let (record, warnings) = get_instrument_data(&source, "my-inst_2".to_string(), true)
+            .await
+            .unwrap();
+        assert_eq!(record.instrument_name.as_deref(), Some("my-inst_2"));
+        assert_eq!(warnings.len(), 1);
+        let err = get_instrument_data(&source, "my \"inst".to_string(), true)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NcasCdlError::InvalidParameter { .. }));
+    }
+
+    fn search(q: Option<&str>, category: Option<&str>, owner: Option<&str>) -> InstrumentQuery {
+        InstrumentQuery {
+            q: q.map(str::to_string),
+            category: category.map(str::to_string),
+            owner: owner.map(str::to_string),
+            page: 1,
+            per_page: 50,
+        }
+    }
+
+    async fn names(query: InstrumentQuery) -> (usize, Vec<String>) {
+        let page = search_instruments(&test_fixtures::cv_source(), query)
+            .await
+            .unwrap();
+        let names = page.instruments.into_iter().map(|i| i.name).collect();
+        (page.total, names)
+    }
+
+    #[tokio::test]
+    async fn instruments_are_searched_by_text_category_and_owner() {
+        let (total, all) = names(search(None, None, None)).await;
+        assert_eq!(total, 3);
+        assert_eq!(all, ["ncas-ceilometer-3", "ncas-lidar-dop-2", "uol-aws-1"]);
+        // Text is found in names, including old ones, descriptors and models
+        for (q, expected) in [
+            ("LIDAR", "ncas-lidar-dop-2"),
+            ("ceilometer-2", "ncas-ceilometer-3"),
+            ("weather station", "uol-aws-1"),
+            ("cr1000", "uol-aws-1"),
+        ] {
+            assert_eq!(
+                names(search(Some(q), None, None)).await.1,
+                [expected],
+                "{}",
+                q
+            );
+        }
+        let (total, _) = names(search(None, Some(" remote SENSING"), None)).await;
+        assert_eq!(total, 2);
+        let query = search(Some("ncas"), None, Some("university of leeds"));
+        assert_eq!(names(query).await, (0, Vec::new()));
+        let query = search(None, Some("Meteorology"), Some("University of Leeds"));
+        assert_eq!(names(query).await.1, ["uol-aws-1"]);
+    }
+
+    #[tokio::test]
+    async fn searches_are_paged() {
+        let mut query = search(None, None, None);
+        query.per_page = 2;
+        query.page = 2;
+        assert_eq!(names(query).await, (3, vec!["uol-aws-1".to_string()]));
+        let mut query = search(None, None, None);
+        query.per_page = 2;
+        query.page = 3;
+        assert_eq!(names(query).await, (3, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn old_names_find_the_renamed_instrument() {
+        let source = test_fixtures::cv_source();
+        for old_name in [
+            "ncas-ceilometer-2",
+            "halo-doppler-lidar-55",
+            "ncas-doppler-lidar-1",
+        ] {
+            let (record, warnings) = get_instrument_data(&source, old_name.to_string(), false)
+                .await
+                .unwrap();
+            let new_name = record.instrument_name.unwrap();
+            assert_eq!(
+                warnings,
+                [format!(
+                    "Instrument {} has been renamed to {}; using {}",
+                    old_name, new_name, new_name
+                )]
+            );
+        }
+        // The current name is used without a warning
+        let (_, warnings) = get_instrument_data(&source, "ncas-lidar-dop-2".to_string(), false)
+            .await
+            .unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn data_products_are_checked_against_the_record() {
+        let records = get_instrument_records(&test_fixtures::cv_source())
+            .await
+            .unwrap();
+        let aws = &records[2];
+        assert_eq!(aws.data_products(), ["surface-met", "aerosol-backscatter"]);
+        for strict in [false, true] {
+            assert_eq!(
+                check_data_product(aws, "surface-met", strict).unwrap(),
+                None
+            );
+            assert_eq!(
+                check_data_product(aws, "aerosol-backscatter", strict).unwrap(),
+                None
+            );
         }
+        let warning = check_data_product(aws, "sea-ice", false).unwrap().unwrap();
+        assert!(warning.contains("uol-aws-1"), "{}", warning);
+        assert!(matches!(
+            check_data_product(aws, "sea-ice", true),
+            Err(NcasCdlError::IncompatibleDataProduct { registered, .. })
+                if registered == aws.data_products()
+        ));
+        // Unregistered instruments have nothing to check against
+        let (unregistered, _) =
+            get_instrument_data(&test_fixtures::cv_source(), "my-inst".to_string(), true)
+                .await
+                .unwrap();
+        assert_eq!(
+            check_data_product(&unregistered, "sea-ice", true).unwrap(),
+            None
+        );
     }
-    Ok(instrument_record)
 }
@@ -1,51 +1,125 @@
 #![allow(dead_code, unused_variables)]
 
-mod cdl;
-mod common;
-mod data_products;
-mod deployments;
-mod instruments;
+pub mod cdl;
+pub mod cdl_parser;
+pub mod common;
+pub mod compliance;
+pub mod cv_cache;
+pub mod cv_source;
+pub mod data_products;
+pub mod deployments;
+pub mod error;
+pub mod instruments;
+pub mod ncml;
 mod netcdf_components;
-use std::error::Error;
+pub mod netcdf_file;
+pub mod profiles;
+pub mod python;
+pub mod rules;
+pub mod template;
+#[cfg(test)]
+mod test_fixtures;
+use common::RegexCache;
+use cv_source::CvSource;
+use error::NcasCdlError;
+use indexmap::IndexMap;
+use netcdf_components::GlobalAttribute;
+use serde::Serialize;
 
+#[derive(Serialize)]
 pub struct CDLData {
     pub filename: String,
-    pub cdl: String,
+    pub template: template::Template,
+    /// Things the user should know about how their request was interpreted.
+    pub warnings: Vec<String>,
 }
 
-pub async fn main(
-    instrument_name: String,
-    data_product: String,
-    deployment: String,
-    start_date: String,
-    tag: String,
-    include_requirement_info: bool,
-) -> Result<CDLData, Box<dyn Error>> {
-    let common = match common::get_common(tag.clone()).await {
-        Ok(common) => common,
-        Err(err) => {
-            return Err(err);
-        }
-    };
-    let instrument_record = match instruments::get_instrument_data(instrument_name.clone()).await {
-        Ok(instrument_record) => instrument_record,
-        Err(err) => {
-            return Err(err);
-        }
-    };
-    let data_product =
-        match data_products::get_data_product(data_product.clone(), tag.clone()).await {
-            Ok(data_product) => data_product,
-            Err(err) => {
-                return Err(err);
-            }
-        };
-    let deployment = match deployments::get_deployment(deployment.clone(), tag.clone()).await {
-        Ok(deployment) => deployment,
-        Err(err) => {
-            return Err(err);
+/// Formats /create-cdl can return the template in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Cdl,
+    Json,
+    Yaml,
+    Ncml,
+    Netcdf,
+    Python,
+}
+
+impl OutputFormat {
+    pub fn from_param(value: &str) -> Result<Self, NcasCdlError> {
+        match value {
+            "cdl" => Ok(OutputFormat::Cdl),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            "ncml" => Ok(OutputFormat::Ncml),
+            "netcdf" => Ok(OutputFormat::Netcdf),
+            "python" => Ok(OutputFormat::Python),
+            _ => Err(NcasCdlError::InvalidParameter {
+                name: "output_format".to_string(),
+                reason: format!(
+                    "expected one of cdl, json, yaml, ncml, netcdf or python, got {:?}",
+                    value
+                ),
+            }),
         }
-    };
+    }
+}
+
+/// What a CDL should be generated for, as given to /create-cdl.
+pub struct CdlRequest {
+    pub instrument_name: String,
+    pub data_product: String,
+    pub deployment: String,
+    pub start_date: String,
+    pub tag: String,
+    pub allow_unregistered_instrument: bool,
+    /// Turn problems that would otherwise be warnings into errors.
+    pub strict: bool,
+    /// Values for global attributes, to use instead of the CVs' examples.
+    pub attribute_values: IndexMap<String, String>,
+    /// Values from a saved profile, which `attribute_values` take precedence over.
+    /// Profiles are shared between data products, so values for attributes this data
+    /// product doesn't have are left out rather than reported.
+    pub profile_values: IndexMap<String, String>,
+}
+
+pub async fn main(
+    source: &dyn CvSource,
+    regexes: &RegexCache,
+    request: CdlRequest,
+) -> Result<CDLData, NcasCdlError> {
+    let CdlRequest {
+        instrument_name,
+        data_product,
+        deployment,
+        start_date,
+        tag,
+        allow_unregistered_instrument,
+        strict,
+        attribute_values,
+        profile_values,
+    } = request;
+    let (common, instrument_record, data_product, deployment) = tokio::join!(
+        common::get_common(source, tag.clone(), regexes),
+        instruments::get_instrument_data(
+            source,
+            instrument_name.clone(),
+            allow_unregistered_instrument
+        ),
+        data_products::get_data_product(source, data_product.clone(), tag.clone()),
+        deployments::get_deployment(source, deployment.clone(), tag.clone()),
+    );
+    // With an unknown tag every file is missing, so check the common attributes first
+    // to report that rather than an unknown data product or deployment mode
+    let common = common?;
+    let (instrument_record, mut warnings) = instrument_record?;
+    let data_product = data_product?;
+    warnings.extend(instruments::check_data_product(
+        &instrument_record,
+        &data_product.name,
+        strict,
+    )?);
+    let deployment = deployment?;
     let mut platform = instrument_record
         .mobile_fixed
         .clone()
@@ -69,22 +143,151 @@ pub async fn main(
         start_date,
         data_product.name
     );
-    let cdl = match cdl::make_cdl(
+    let global_attributes: Vec<&GlobalAttribute> = common
+        .global_attributes
+        .iter()
+        .chain(&data_product.global_attributes)
+        .collect();
+    let mut values: IndexMap<String, String> = profile_values
+        .into_iter()
+        .filter(|(name, _)| global_attributes.iter().any(|attr| &attr.name == name))
+        .collect();
+    values.extend(attribute_values);
+    let template = template::make_template(
         file_name.clone(),
-        common,
-        deployment,
-        data_product,
-        instrument_record,
-        include_requirement_info,
-    ) {
-        Ok(cdl) => cdl,
-        Err(err) => {
-            return Err(err);
+        &common,
+        &deployment,
+        &data_product,
+        &instrument_record,
+        &values,
+    )?;
+    let mut problems = rules::check_global_attributes(&global_attributes, &values).problems();
+    // Values the instrument's record fixes, such as instrument_model, are kept
+    for attr in &template.global_attributes {
+        let (Some(given), template::AttributeValue::Text(value)) =
+            (values.get(&attr.name), &attr.value)
+        else {
+            continue;
+        };
+        // Differences from values the CVs fix have already been reported
+        let fixed_by_cv = global_attributes
+            .iter()
+            .any(|cv_attr| cv_attr.name == attr.name && !cv_attr.value.is_empty());
+        if given != value && !fixed_by_cv {
+            problems.push(format!(
+                "{} comes from the instrument's record as {:?}, so {:?} was not used",
+                attr.name, value, given
+            ));
         }
-    };
+    }
+    if strict && !problems.is_empty() {
+        return Err(NcasCdlError::InvalidAttributeValues(problems));
+    }
+    warnings.extend(problems);
     let cdl_data = CDLData {
         filename: file_name,
-        cdl,
+        template,
+        warnings,
     };
     Ok(cdl_data)
 }
+
+/// Checks values for global attributes against what the common and data product CVs
+/// say about them, before they are put in a file.
+pub async fn check_global_attributes(
+    source: &dyn CvSource,
+    regexes: &RegexCache,
+    data_product: String,
+    tag: String,
+    values: &IndexMap<String, String>,
+) -> Result<rules::AttributeReport, NcasCdlError> {
+    let (common, data_product) = tokio::join!(
+        common::get_common(source, tag.clone(), regexes),
+        data_products::get_data_product(source, data_product, tag),
+    );
+    let (common, data_product) = (common?, data_product?);
+    let attributes: Vec<&GlobalAttribute> = common
+        .global_attributes
+        .iter()
+        .chain(&data_product.global_attributes)
+        .collect();
+    Ok(rules::check_global_attributes(&attributes, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use template::{AttributeValue, TemplateAttribute};
+
+    fn string_map(pairs: &[(&str, &str)]) -> IndexMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    async fn create(request: CdlRequest) -> Result<CDLData, NcasCdlError> {
+        main(&test_fixtures::cv_source(), &RegexCache::default(), request).await
+    }
+
+    fn global_attribute<'a>(cdl_data: &'a CDLData, name: &str) -> &'a TemplateAttribute {
+        let mut attributes = cdl_data.template.global_attributes.iter();
+        attributes.find(|attr| attr.name == name).unwrap()
+    }
+
+    fn text(value: &str) -> AttributeValue {
+        AttributeValue::Text(value.to_string())
+    }
+
+    #[tokio::test]
+    async fn given_values_fill_the_template() {
+        let cdl_data = test_fixtures::cdl_data(test_fixtures::attribute_values()).await;
+        assert!(cdl_data.warnings.is_empty(), "{:?}", cdl_data.warnings);
+        let title = global_attribute(&cdl_data, "title");
+        assert_eq!(title.value, text("Ceilometer backscatter at CAO"));
+        assert!(title.fixed);
+        // Attributes without a value keep the example
+        let cdl_data = test_fixtures::cdl_data(IndexMap::new()).await;
+        let title = global_attribute(&cdl_data, "title");
+        assert_eq!(title.value, text("EXAMPLE: My title"));
+        assert!(!title.fixed);
+    }
+
+    #[tokio::test]
+    async fn values_are_checked_against_the_rules() {
+        let mut values = test_fixtures::attribute_values();
+        values.insert("laser_wavelength".to_string(), "905nm".to_string());
+        values.insert("platform".to_string(), "cao".to_string());
+        let mut request = test_fixtures::request("ncas-ceilometer-3", values);
+        let Err(NcasCdlError::InvalidAttributeValues(problems)) = create(request).await else {
+            panic!("invalid values were accepted");
+        };
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+
+        request = test_fixtures::request("ncas-ceilometer-3", string_map(&[("platform", "cao")]));
+        request.strict = false;
+        let cdl_data = create(request).await.unwrap();
+        assert_eq!(cdl_data.warnings.len(), 1, "{:?}", cdl_data.warnings);
+        assert!(cdl_data.warnings[0].contains("platform"));
+    }
+
+    #[tokio::test]
+    async fn given_values_take_precedence_over_profile_values() {
+        let mut request = test_fixtures::request(
+            "ncas-ceilometer-3",
+            string_map(&[("creator_name", "Jane Doe")]),
+        );
+        // Profiles are shared between data products, so platform isn't reported
+        request.profile_values = string_map(&[
+            ("creator_name", "Profile Person"),
+            ("title", "Title from the profile"),
+            ("platform", "cao"),
+        ]);
+        let cdl_data = create(request).await.unwrap();
+        assert!(cdl_data.warnings.is_empty(), "{:?}", cdl_data.warnings);
+        let creator_name = global_attribute(&cdl_data, "creator_name");
+        assert_eq!(creator_name.value, text("Jane Doe"));
+        let title = global_attribute(&cdl_data, "title");
+        assert_eq!(title.value, text("Title from the profile"));
+    }
+}
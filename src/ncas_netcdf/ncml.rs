@@ -0,0 +1,193 @@
+use super::template::{
+    AttributeValue, DataType, RequirementLevel, Template, TemplateAttribute, TemplateDimension,
+    TemplateVariable,
+};
+use std::collections::HashMap;
+
+const NCML_NAMESPACE: &str = "http://www.unidata.ucar.edu/namespaces/netcdf/ncml-2.2";
+
+// Escape text for use in a double quoted XML attribute. Whitespace other than spaces is
+// written as character references so parsers don't normalise it away, and other control
+// characters, which XML 1.0 doesn't allow at all, are replaced.
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            c if c.is_control() => escaped.push('\u{FFFD}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// NcML names the 64 bit integers and strings differently to CDL
+fn ncml_type(data_type: DataType) -> &'static str {
+    match data_type {
+        DataType::Int64 => "long",
+        DataType::Uint64 => "ulong",
+        DataType::String => "String",
+        data_type => data_type.name(),
+    }
+}
+
+// Append the requirement as a comment after an element, if asked for
+fn with_requirement(
+    text: String,
+    requirement: &RequirementLevel,
+    requirement_info: bool,
+) -> String {
+    if requirement_info {
+        // "--" isn't allowed inside XML comments
+        let mut comment = requirement.to_string();
+        while comment.contains("--") {
+            comment = comment.replace("--", "- -");
+        }
+        format!("{} <!-- {} -->\n", text, comment)
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+fn create_attribute_text(indent: &str, attr: &TemplateAttribute, requirement_info: bool) -> String {
+    // Text attributes are char in CDL, but NcML gives them the String type
+    let (attr_type, value) = match &attr.value {
+        AttributeValue::Text(value) => ("String", escape_xml(value)),
+        AttributeValue::Numbers(numbers) => (
+            ncml_type(attr.data_type),
+            numbers
+                .iter()
+                .map(|number| number.to_string())
+                .collect::<Vec<String>>()
+                .join(" "),
+        ),
+    };
+    let attr_text = format!(
+        "{}<attribute name=\"{}\" type=\"{}\" value=\"{}\"/>",
+        indent,
+        escape_xml(&attr.name),
+        attr_type,
+        value
+    );
+    with_requirement(attr_text, &attr.requirement, requirement_info)
+}
+
+// NcML needs an integer length, so dimensions whose length depends on the data are
+// unlimited unless a length is given for them
+fn create_dimension_text(
+    dimension: &TemplateDimension,
+    dimension_lengths: &HashMap<String, u64>,
+    requirement_info: bool,
+) -> String {
+    let given_length = dimension
+        .length
+        .map(u64::from)
+        .or_else(|| dimension_lengths.get(&dimension.name).copied());
+    let dim_length = match given_length {
+        Some(length) => format!("length=\"{}\"", length),
+        None => "length=\"0\" isUnlimited=\"true\"".to_string(),
+    };
+    let dimension_text = format!(
+        "  <dimension name=\"{}\" {}/>",
+        escape_xml(&dimension.name),
+        dim_length
+    );
+    with_requirement(dimension_text, &dimension.requirement, requirement_info)
+}
+
+fn create_variable_text(variable: &TemplateVariable, requirement_info: bool) -> String {
+    // Scalar variables have no shape
+    let shape = if variable.dimensions.is_empty() {
+        "".to_string()
+    } else {
+        format!(" shape=\"{}\"", escape_xml(&variable.dimensions.join(" ")))
+    };
+    let opening_tag = format!(
+        "  <variable name=\"{}\"{} type=\"{}\">",
+        escape_xml(&variable.name),
+        shape,
+        ncml_type(variable.data_type)
+    );
+    let mut variable_text = with_requirement(opening_tag, &variable.requirement, requirement_info);
+    for attr in &variable.attributes {
+        variable_text.push_str(&create_attribute_text("    ", attr, requirement_info));
+    }
+    variable_text.push_str("  </variable>\n");
+    variable_text
+}
+
+/// Writes the template as NcML. Dimensions the CVs don't give a length for take theirs
+/// from `dimension_lengths`, or are unlimited. There's no `location`, as the template
+/// doesn't describe a file that exists.
+pub fn make_ncml(
+    template: &Template,
+    dimension_lengths: &HashMap<String, u64>,
+    requirement_info: bool,
+) -> String {
+    let mut ncml = String::new();
+    ncml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    ncml.push_str(&format!("<netcdf xmlns=\"{}\">\n", NCML_NAMESPACE));
+    for dimension in &template.dimensions {
+        ncml.push_str(&create_dimension_text(
+            dimension,
+            dimension_lengths,
+            requirement_info,
+        ));
+    }
+    for variable in &template.variables {
+        ncml.push_str(&create_variable_text(variable, requirement_info));
+    }
+    ncml.push_str("  <!-- global attributes -->\n");
+    for attr in &template.global_attributes {
+        ncml.push_str(&create_attribute_text("  ", attr, requirement_info));
+    }
+    ncml.push_str("</netcdf>\n");
+    ncml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::test_fixtures;
+
+    #[test]
+    fn attribute_text_is_escaped() {
+        assert_eq!(
+            escape_xml("a<b> & \"c\" 'd'\n\t\r\x07é"),
+            "a&lt;b&gt; &amp; &quot;c&quot; &apos;d&apos;&#10;&#9;&#13;\u{FFFD}é"
+        );
+        let comment = with_requirement(
+            "<x/>".to_string(),
+            &RequirementLevel::RequiredIf("a---b".to_string()),
+            true,
+        );
+        assert_eq!(comment, "<x/> <!-- Required if a- - -b -->\n");
+    }
+
+    #[tokio::test]
+    async fn dimensions_without_lengths_are_unlimited() {
+        let cdl_data = test_fixtures::cdl_data(test_fixtures::attribute_values()).await;
+        let lengths = HashMap::from([("altitude".to_string(), 200)]);
+        let ncml = make_ncml(&cdl_data.template, &lengths, false);
+        let expected_lines = [
+            "  <dimension name=\"time\" length=\"0\" isUnlimited=\"true\"/>",
+            "  <dimension name=\"latitude\" length=\"1\"/>",
+            "  <dimension name=\"altitude\" length=\"200\"/>",
+            "  <variable name=\"latitude\" type=\"float\">",
+            "  <variable name=\"qc_flag\" shape=\"time altitude\" type=\"byte\">",
+            "    <attribute name=\"flag_values\" type=\"byte\" value=\"0 1 2\"/>",
+            "    <attribute name=\"_FillValue\" type=\"float\" value=\"-1e20\"/>",
+            "  <attribute name=\"title\" type=\"String\" value=\"Ceilometer backscatter at CAO\"/>",
+        ];
+        for line in expected_lines {
+            assert!(ncml.lines().any(|l| l == line), "{}\n{}", line, ncml);
+        }
+    }
+}
@@ -1,10 +1,16 @@
+use super::rules::WholeValueRegex;
+use indexmap::IndexMap;
 use serde::Deserialize;
-use std::collections::HashMap;
 
 #[derive(Debug, Deserialize)]
 pub struct Variable {
     pub name: String,
-    pub attributes: HashMap<String, String>,
+    /// Attributes in the order the CV lists them.
+    pub attributes: IndexMap<String, String>,
+    /// Compliance checking rules for the attributes that have one, by attribute name.
+    pub rules: IndexMap<String, String>,
+    /// Path of the CV file the variable is defined in.
+    pub source: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,4 +25,7 @@ pub struct GlobalAttribute {
     pub value: String,
     pub example: String,
     pub compliance: String,
+    /// A pattern the whole value must match, compiled from the CV's regex check column.
+    #[serde(skip)]
+    pub regex_check: Option<WholeValueRegex>,
 }
@@ -0,0 +1,472 @@
+use super::error::NcasCdlError;
+use super::template::{AttributeValue, DataType, Number, Template, TemplateAttribute};
+use std::collections::HashMap;
+
+// Tags and type codes from the NetCDF classic format specification
+const NC_DIMENSION: u32 = 0x0A;
+const NC_VARIABLE: u32 = 0x0B;
+const NC_ATTRIBUTE: u32 = 0x0C;
+
+fn nc_type(data_type: DataType) -> Option<u32> {
+    match data_type {
+        DataType::Byte => Some(1),
+        DataType::Char => Some(2),
+        DataType::Short => Some(3),
+        DataType::Int => Some(4),
+        DataType::Float => Some(5),
+        DataType::Double => Some(6),
+        DataType::Ubyte => Some(7),
+        DataType::Ushort => Some(8),
+        DataType::Uint => Some(9),
+        DataType::Int64 => Some(10),
+        DataType::Uint64 => Some(11),
+        // Variable length strings need the HDF5 based format
+        DataType::String => None,
+    }
+}
+
+fn type_size(data_type: DataType) -> u64 {
+    match data_type {
+        DataType::Byte | DataType::Ubyte | DataType::Char | DataType::String => 1,
+        DataType::Short | DataType::Ushort => 2,
+        DataType::Int | DataType::Uint | DataType::Float => 4,
+        DataType::Int64 | DataType::Uint64 | DataType::Double => 8,
+    }
+}
+
+// The types CDF-1 and CDF-2 have. The rest need CDF-5.
+fn is_classic_type(data_type: DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Byte
+            | DataType::Char
+            | DataType::Short
+            | DataType::Int
+            | DataType::Float
+            | DataType::Double
+    )
+}
+
+// What netCDF-C fills unwritten values with when a variable has no _FillValue
+fn default_fill(data_type: DataType) -> Number {
+    match data_type {
+        DataType::Byte => Number::Signed(-127),
+        DataType::Char | DataType::String => Number::Unsigned(0),
+        DataType::Short => Number::Signed(-32767),
+        DataType::Int => Number::Signed(-2147483647),
+        DataType::Float | DataType::Double => Number::Float(9.969209968386869e36),
+        DataType::Ubyte => Number::Unsigned(255),
+        DataType::Ushort => Number::Unsigned(65535),
+        DataType::Uint => Number::Unsigned(4294967295),
+        DataType::Int64 => Number::Signed(-9223372036854775806),
+        DataType::Uint64 => Number::Unsigned(18446744073709551614),
+    }
+}
+
+// Big endian bytes of a number stored as the given type. Values in the template have
+// already been checked to fit their type.
+fn number_bytes(number: Number, data_type: DataType) -> Vec<u8> {
+    let (signed, unsigned, float) = match number {
+        Number::Signed(v) => (v, v as u64, v as f64),
+        Number::Unsigned(v) => (v as i64, v, v as f64),
+        Number::Float(v) => (v as i64, v as u64, v),
+    };
+    match data_type {
+        DataType::Byte => (signed as i8).to_be_bytes().to_vec(),
+        DataType::Ubyte | DataType::Char | DataType::String => vec![unsigned as u8],
+        DataType::Short => (signed as i16).to_be_bytes().to_vec(),
+        DataType::Ushort => (unsigned as u16).to_be_bytes().to_vec(),
+        DataType::Int => (signed as i32).to_be_bytes().to_vec(),
+        DataType::Uint => (unsigned as u32).to_be_bytes().to_vec(),
+        DataType::Int64 => signed.to_be_bytes().to_vec(),
+        DataType::Uint64 => unsigned.to_be_bytes().to_vec(),
+        DataType::Float => (float as f32).to_be_bytes().to_vec(),
+        DataType::Double => float.to_be_bytes().to_vec(),
+    }
+}
+
+// Rounds up to the 4 byte boundary everything in the file is aligned to
+fn padded(size: u64) -> u64 {
+    size.div_ceil(4) * 4
+}
+
+fn output_failed(reason: String) -> NcasCdlError {
+    NcasCdlError::OutputFailed {
+        format: "netcdf".to_string(),
+        reason,
+    }
+}
+
+struct Header {
+    buf: Vec<u8>,
+    // Counts and dimension ids are 8 bytes in CDF-5, and 4 bytes before that
+    cdf5: bool,
+}
+
+impl Header {
+    fn tag(&mut self, tag: u32) {
+        self.buf.extend(tag.to_be_bytes());
+    }
+
+    fn non_neg(&mut self, n: u64) {
+        if self.cdf5 {
+            self.buf.extend(n.to_be_bytes());
+        } else {
+            self.buf.extend((n as u32).to_be_bytes());
+        }
+    }
+
+    fn pad(&mut self) {
+        self.buf.resize(padded(self.buf.len() as u64) as usize, 0);
+    }
+
+    fn name(&mut self, name: &str) {
+        self.non_neg(name.len() as u64);
+        self.buf.extend(name.as_bytes());
+        self.pad();
+    }
+
+    fn list(&mut self, tag: u32, len: usize) {
+        if len == 0 {
+            // ABSENT
+            self.tag(0);
+            self.non_neg(0);
+        } else {
+            self.tag(tag);
+            self.non_neg(len as u64);
+        }
+    }
+
+    fn attributes(&mut self, attributes: &[TemplateAttribute]) {
+        self.list(NC_ATTRIBUTE, attributes.len());
+        for attr in attributes {
+            self.name(&attr.name);
+            match &attr.value {
+                AttributeValue::Text(value) => {
+                    self.tag(2);
+                    self.non_neg(value.len() as u64);
+                    self.buf.extend(value.as_bytes());
+                }
+                AttributeValue::Numbers(numbers) => {
+                    self.tag(nc_type(attr.data_type).unwrap_or(2));
+                    self.non_neg(numbers.len() as u64);
+                    for number in numbers {
+                        self.buf.extend(number_bytes(*number, attr.data_type));
+                    }
+                }
+            }
+            self.pad();
+        }
+    }
+}
+
+/// Writes the template as a NetCDF classic file with every variable filled with its
+/// _FillValue. This is CDF-2 (64-bit offset), or CDF-5 when the template uses the
+/// unsigned or 64-bit integer types that CDF-2 doesn't have.
+///
+/// Dimensions the CVs don't give a length for take theirs from `dimension_lengths`.
+/// Files larger than `max_bytes` are refused rather than built in memory.
+pub fn make_netcdf(
+    template: &Template,
+    dimension_lengths: &HashMap<String, u64>,
+    max_bytes: u64,
+) -> Result<Vec<u8>, NcasCdlError> {
+    let mut lengths = Vec::new();
+    for dimension in &template.dimensions {
+        let length = match dimension.length {
+            Some(length) => u64::from(length),
+            None => *dimension_lengths
+                .get(&dimension.name)
+                .ok_or_else(|| NcasCdlError::MissingParameter(format!("dim_{}", dimension.name)))?,
+        };
+        lengths.push(length);
+    }
+
+    // Work out the shape and size of every variable before writing anything
+    let mut cdf5 = false;
+    let mut shapes = Vec::new();
+    let mut data_size: u64 = 0;
+    for variable in &template.variables {
+        if nc_type(variable.data_type).is_none() {
+            return Err(output_failed(format!(
+                "variable {} has type {}, which NetCDF classic files can't hold",
+                variable.name,
+                variable.data_type.name()
+            )));
+        }
+        cdf5 |= !is_classic_type(variable.data_type)
+            || variable
+                .attributes
+                .iter()
+                .any(|attr| !is_classic_type(attr.data_type));
+        let mut dimension_ids = Vec::new();
+        let mut count: u64 = 1;
+        for dimension_name in &variable.dimensions {
+            let id = template
+                .dimensions
+                .iter()
+                .position(|dimension| &dimension.name == dimension_name)
+                .ok_or_else(|| {
+                    output_failed(format!(
+                        "variable {} uses dimension {}, which isn't defined",
+                        variable.name, dimension_name
+                    ))
+                })?;
+            dimension_ids.push(id as u64);
+            count = count.saturating_mul(lengths[id]);
+        }
+        let size = padded(count.saturating_mul(type_size(variable.data_type)));
+        data_size = data_size.saturating_add(size);
+        shapes.push((dimension_ids, count, size));
+    }
+    cdf5 |= template
+        .global_attributes
+        .iter()
+        .any(|attr| !is_classic_type(attr.data_type));
+    if data_size > max_bytes {
+        return Err(NcasCdlError::OutputTooLarge {
+            bytes: data_size,
+            limit: max_bytes,
+        });
+    }
+
+    let mut header = Header {
+        buf: Vec::new(),
+        cdf5,
+    };
+    header.buf.extend(b"CDF");
+    header.buf.push(if cdf5 { 5 } else { 2 });
+    // No record dimension, so no records
+    header.non_neg(0);
+    header.list(NC_DIMENSION, template.dimensions.len());
+    for (dimension, length) in template.dimensions.iter().zip(&lengths) {
+        header.name(&dimension.name);
+        header.non_neg(*length);
+    }
+    header.attributes(&template.global_attributes);
+    header.list(NC_VARIABLE, template.variables.len());
+    // Where each variable's offset goes, to fill in once the header's size is known
+    let mut begin_positions = Vec::new();
+    for (variable, (dimension_ids, _, size)) in template.variables.iter().zip(&shapes) {
+        header.name(&variable.name);
+        header.non_neg(dimension_ids.len() as u64);
+        for id in dimension_ids {
+            header.non_neg(*id);
+        }
+        header.attributes(&variable.attributes);
+        header.tag(nc_type(variable.data_type).unwrap_or(2));
+        // CDF-2 readers ignore vsize when it doesn't fit
+        header.non_neg(if cdf5 {
+            *size
+        } else {
+            (*size).min(u32::MAX as u64)
+        });
+        begin_positions.push(header.buf.len());
+        header.buf.extend(0u64.to_be_bytes());
+    }
+
+    let mut file = header.buf;
+    file.reserve(data_size as usize);
+    for ((variable, (_, count, size)), begin_position) in
+        template.variables.iter().zip(&shapes).zip(begin_positions)
+    {
+        let begin = file.len() as u64;
+        file[begin_position..begin_position + 8].copy_from_slice(&begin.to_be_bytes());
+        let fill = variable
+            .attributes
+            .iter()
+            .find(|attr| attr.name == "_FillValue")
+            .and_then(|attr| match &attr.value {
+                AttributeValue::Numbers(numbers) => numbers.first().copied(),
+                AttributeValue::Text(text) => {
+                    text.bytes().next().map(|b| Number::Unsigned(b.into()))
+                }
+            })
+            .unwrap_or_else(|| default_fill(variable.data_type));
+        let fill = number_bytes(fill, variable.data_type);
+        for _ in 0..*count {
+            file.extend(&fill);
+        }
+        file.resize((begin + size) as usize, 0);
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::test_fixtures;
+    use indexmap::IndexMap;
+
+    // Reads back the parts of a CDF-2 header the writer is responsible for
+    struct Reader<'a> {
+        buf: &'a [u8],
+        position: usize,
+    }
+
+    struct DecodedVariable {
+        name: String,
+        dimension_ids: Vec<u32>,
+        nc_type: u32,
+        vsize: u32,
+        begin: u64,
+    }
+
+    impl Reader<'_> {
+        fn bytes(&mut self, count: usize) -> &[u8] {
+            let bytes = &self.buf[self.position..self.position + count];
+            self.position += count;
+            bytes
+        }
+
+        fn u32(&mut self) -> u32 {
+            u32::from_be_bytes(self.bytes(4).try_into().unwrap())
+        }
+
+        fn u64(&mut self) -> u64 {
+            u64::from_be_bytes(self.bytes(8).try_into().unwrap())
+        }
+
+        fn name(&mut self) -> String {
+            let length = self.u32() as usize;
+            let name = String::from_utf8(self.bytes(length).to_vec()).unwrap();
+            self.position = padded(self.position as u64) as usize;
+            name
+        }
+
+        fn list(&mut self, tag: u32) -> u32 {
+            let found = self.u32();
+            let count = self.u32();
+            assert!(found == tag || (found == 0 && count == 0));
+            count
+        }
+
+        fn attribute_names(&mut self) -> Vec<String> {
+            let count = self.list(NC_ATTRIBUTE);
+            let mut names = Vec::new();
+            for _ in 0..count {
+                names.push(self.name());
+                let nc_type = self.u32();
+                let length = self.u32() as u64;
+                let size = match nc_type {
+                    1 | 2 => length,
+                    3 => length * 2,
+                    4 | 5 => length * 4,
+                    _ => length * 8,
+                };
+                self.position += padded(size) as usize;
+            }
+            names
+        }
+    }
+
+    fn lengths(lengths: &[(&str, u64)]) -> HashMap<String, u64> {
+        lengths
+            .iter()
+            .map(|(name, length)| (name.to_string(), *length))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn header_describes_the_template() {
+        let template = test_fixtures::cdl_data(IndexMap::new()).await.template;
+        let dimension_lengths = lengths(&[("time", 3), ("altitude", 2)]);
+        let file = make_netcdf(&template, &dimension_lengths, 1024).unwrap();
+        let mut reader = Reader {
+            buf: &file,
+            position: 0,
+        };
+
+        assert_eq!(reader.bytes(4), b"CDF\x02");
+        assert_eq!(reader.u32(), 0);
+        let mut dimensions = Vec::new();
+        for _ in 0..reader.list(NC_DIMENSION) {
+            dimensions.push((reader.name(), reader.u32()));
+        }
+        assert_eq!(
+            dimensions,
+            [
+                ("time".to_string(), 3),
+                ("latitude".to_string(), 1),
+                ("altitude".to_string(), 2)
+            ]
+        );
+        assert_eq!(
+            reader.attribute_names(),
+            [
+                "Conventions",
+                "creator_name",
+                "time_coverage_start",
+                "title",
+                "laser_wavelength"
+            ]
+        );
+        let mut variables = Vec::new();
+        for _ in 0..reader.list(NC_VARIABLE) {
+            let name = reader.name();
+            let dimension_count = reader.u32();
+            let dimension_ids = (0..dimension_count).map(|_| reader.u32()).collect();
+            reader.attribute_names();
+            variables.push(DecodedVariable {
+                name,
+                dimension_ids,
+                nc_type: reader.u32(),
+                vsize: reader.u32(),
+                begin: reader.u64(),
+            });
+        }
+        let header_size = reader.position as u64;
+
+        let shapes: Vec<(&str, Vec<u32>, u32, u32)> = variables
+            .iter()
+            .map(|v| (v.name.as_str(), v.dimension_ids.clone(), v.nc_type, v.vsize))
+            .collect();
+        assert_eq!(
+            shapes,
+            [
+                ("time", vec![0], 6, 24),
+                ("latitude", vec![], 5, 4),
+                ("altitude", vec![0], 5, 12),
+                // 6 bytes of data, padded to 8
+                ("qc_flag", vec![0, 2], 1, 8),
+                ("count", vec![0], 4, 12),
+            ]
+        );
+        // The data follows the header, one variable after another
+        let mut begin = header_size;
+        for variable in &variables {
+            assert_eq!(variable.begin, begin, "{}", variable.name);
+            begin += u64::from(variable.vsize);
+        }
+        assert_eq!(file.len() as u64, begin);
+
+        let data = |index: usize| {
+            let variable = &variables[index];
+            &file[variable.begin as usize..(variable.begin + u64::from(variable.vsize)) as usize]
+        };
+        // _FillValue where the variable has one, otherwise netCDF-C's default fill
+        assert_eq!(data(0), 9.969209968386869e36f64.to_be_bytes().repeat(3));
+        assert_eq!(data(1), 9.96921e36f32.to_be_bytes());
+        assert_eq!(data(2), (-1e20f32).to_be_bytes().repeat(3));
+        assert_eq!(data(3), [0; 8]);
+        assert_eq!(data(4), (-2147483647i32).to_be_bytes().repeat(3));
+    }
+
+    #[tokio::test]
+    async fn dimensions_without_a_length_must_be_given_one() {
+        let template = test_fixtures::cdl_data(IndexMap::new()).await.template;
+        let err = make_netcdf(&template, &lengths(&[("time", 3)]), 1024).unwrap_err();
+        assert!(matches!(err, NcasCdlError::MissingParameter(name) if name == "dim_altitude"));
+    }
+
+    #[tokio::test]
+    async fn large_files_are_refused() {
+        let template = test_fixtures::cdl_data(IndexMap::new()).await.template;
+        let dimension_lengths = lengths(&[("time", 1000), ("altitude", 1000)]);
+        let err = make_netcdf(&template, &dimension_lengths, 1024).unwrap_err();
+        assert!(matches!(
+            err,
+            NcasCdlError::OutputTooLarge { limit: 1024, .. }
+        ));
+    }
+}
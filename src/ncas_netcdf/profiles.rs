@@ -0,0 +1,209 @@
+use super::error::NcasCdlError;
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// A named set of global attribute values, such as a team's creator, institution and
+/// project, for /create-cdl to fill in.
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+    pub name: String,
+    pub attributes: IndexMap<String, String>,
+}
+
+type Profiles = IndexMap<String, IndexMap<String, String>>;
+
+/// Profiles kept in a JSON file of profile names to attribute values.
+///
+/// The whole file is held in memory and rewritten on every change, which is fine for
+/// the handful of profiles a team keeps.
+pub struct ProfileStore {
+    path: PathBuf,
+    profiles: Mutex<Profiles>,
+}
+
+fn store_failed(path: &Path, err: impl std::fmt::Display) -> NcasCdlError {
+    NcasCdlError::ProfileStoreFailed {
+        location: path.display().to_string(),
+        reason: err.to_string(),
+    }
+}
+
+// Names go in URLs, so keep them to letters, digits, dots, dashes and underscores
+fn check_name(name: &str) -> Result<(), NcasCdlError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if valid {
+        Ok(())
+    } else {
+        Err(NcasCdlError::InvalidParameter {
+            name: "profile".to_string(),
+            reason: format!(
+                "names must be 1 to {} letters, digits, dots, dashes or underscores, got {:?}",
+                MAX_NAME_LENGTH, name
+            ),
+        })
+    }
+}
+
+impl ProfileStore {
+    /// Loads the profiles from `path`. A file that doesn't exist yet is no profiles.
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self, NcasCdlError> {
+        let path = path.into();
+        let profiles = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| store_failed(&path, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Profiles::new(),
+            Err(e) => return Err(store_failed(&path, e)),
+        };
+        Ok(ProfileStore {
+            path,
+            profiles: Mutex::new(profiles),
+        })
+    }
+
+    // Write to a temporary file first so a failed write doesn't lose every profile
+    async fn save(&self, profiles: &Profiles) -> Result<(), NcasCdlError> {
+        let contents =
+            serde_json::to_string_pretty(profiles).map_err(|e| store_failed(&self.path, e))?;
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, contents)
+            .await
+            .map_err(|e| store_failed(&self.path, e))?;
+        tokio::fs::rename(&temp_path, &self.path)
+            .await
+            .map_err(|e| store_failed(&self.path, e))
+    }
+
+    pub async fn list(&self) -> Vec<Profile> {
+        let profiles = self.profiles.lock().await;
+        profiles
+            .iter()
+            .map(|(name, attributes)| Profile {
+                name: name.clone(),
+                attributes: attributes.clone(),
+            })
+            .collect()
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Profile, NcasCdlError> {
+        let profiles = self.profiles.lock().await;
+        match profiles.get(name) {
+            Some(attributes) => Ok(Profile {
+                name: name.to_string(),
+                attributes: attributes.clone(),
+            }),
+            None => Err(NcasCdlError::UnknownProfile(name.to_string())),
+        }
+    }
+
+    /// Creates or replaces a profile, returning whether it is new.
+    pub async fn put(
+        &self,
+        name: &str,
+        attributes: IndexMap<String, String>,
+    ) -> Result<bool, NcasCdlError> {
+        check_name(name)?;
+        let mut profiles = self.profiles.lock().await;
+        let mut updated = profiles.clone();
+        let created = updated.insert(name.to_string(), attributes).is_none();
+        self.save(&updated).await?;
+        *profiles = updated;
+        Ok(created)
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), NcasCdlError> {
+        let mut profiles = self.profiles.lock().await;
+        if !profiles.contains_key(name) {
+            return Err(NcasCdlError::UnknownProfile(name.to_string()));
+        }
+        let mut updated = profiles.clone();
+        updated.shift_remove(name);
+        self.save(&updated).await?;
+        *profiles = updated;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A path in a new temporary directory, so tests don't see each other's files
+    fn store_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("profiles-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("profiles.json")
+    }
+
+    fn attributes(creator_name: &str) -> IndexMap<String, String> {
+        IndexMap::from([("creator_name".to_string(), creator_name.to_string())])
+    }
+
+    #[tokio::test]
+    async fn profiles_are_kept_in_the_file() {
+        let path = store_path("crud");
+        let store = ProfileStore::open(&path).await.unwrap();
+        assert!(store.list().await.is_empty());
+        assert!(store.put("team-a", attributes("Jane Doe")).await.unwrap());
+        assert!(store.put("team_b.2", attributes("John Doe")).await.unwrap());
+        assert!(!store.put("team-a", attributes("Jane Smith")).await.unwrap());
+
+        let store = ProfileStore::open(&path).await.unwrap();
+        let names: Vec<String> = store.list().await.into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["team-a", "team_b.2"]);
+        let profile = store.get("team-a").await.unwrap();
+        assert_eq!(profile.attributes, attributes("Jane Smith"));
+
+        store.delete("team-a").await.unwrap();
+        assert!(matches!(
+            store.get("team-a").await,
+            Err(NcasCdlError::UnknownProfile(_))
+        ));
+        assert!(matches!(
+            store.delete("team-a").await,
+            Err(NcasCdlError::UnknownProfile(_))
+        ));
+        let store = ProfileStore::open(&path).await.unwrap();
+        assert_eq!(store.list().await.len(), 1);
+    }
+
+    #[test]
+    fn names_must_be_safe_in_urls() {
+        for name in ["a", "team-a", "Team_B.2", &"x".repeat(MAX_NAME_LENGTH)] {
+            assert!(check_name(name).is_ok(), "{}", name);
+        }
+        let too_long = "x".repeat(MAX_NAME_LENGTH + 1);
+        for name in ["", "a b", "../a", "a/b", "café", &too_long] {
+            assert!(
+                matches!(check_name(name), Err(NcasCdlError::InvalidParameter { .. })),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unreadable_stores_are_reported() {
+        let path = store_path("invalid");
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(
+            ProfileStore::open(&path).await,
+            Err(NcasCdlError::ProfileStoreFailed { .. })
+        ));
+        // A directory can't be read as a file
+        let path = path.parent().unwrap().to_path_buf();
+        assert!(matches!(
+            ProfileStore::open(&path).await,
+            Err(NcasCdlError::ProfileStoreFailed { .. })
+        ));
+    }
+}
@@ -0,0 +1,238 @@
+use super::template::{AttributeValue, DataType, RequirementLevel, Template, TemplateAttribute};
+
+// Quote a value as a Python string literal
+fn python_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The numpy type with the same size and sign as a NetCDF type
+fn numpy_type(data_type: DataType) -> &'static str {
+    match data_type {
+        DataType::Byte => "int8",
+        DataType::Ubyte => "uint8",
+        DataType::Short => "int16",
+        DataType::Ushort => "uint16",
+        DataType::Int => "int32",
+        DataType::Uint => "uint32",
+        DataType::Int64 => "int64",
+        DataType::Uint64 => "uint64",
+        DataType::Float => "float32",
+        DataType::Double => "float64",
+        DataType::Char => "S1",
+        DataType::String => "str",
+    }
+}
+
+// The datatype argument createVariable needs for a NetCDF type
+fn variable_datatype(data_type: DataType) -> String {
+    match data_type {
+        // Variable length strings are asked for with the str type itself
+        DataType::String => "str".to_string(),
+        data_type => python_string(numpy_type(data_type)),
+    }
+}
+
+fn python_value(attr: &TemplateAttribute) -> String {
+    match &attr.value {
+        AttributeValue::Text(value) => python_string(value),
+        AttributeValue::Numbers(numbers) if numbers.len() == 1 => {
+            format!("np.{}({})", numpy_type(attr.data_type), numbers[0])
+        }
+        AttributeValue::Numbers(numbers) => {
+            let values: Vec<String> = numbers.iter().map(|number| number.to_string()).collect();
+            format!(
+                "np.array([{}], dtype={})",
+                values.join(", "),
+                python_string(numpy_type(attr.data_type))
+            )
+        }
+    }
+}
+
+// End a line with the requirement level, if asked for
+fn with_requirement(
+    text: String,
+    requirement: &RequirementLevel,
+    requirement_info: bool,
+) -> String {
+    if requirement_info {
+        format!("{}  # {}\n", text, requirement)
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+fn attributes_text(target: &str, attributes: &[&TemplateAttribute]) -> String {
+    if attributes.is_empty() {
+        return "".to_string();
+    }
+    let mut text = format!("{}.setncatts({{\n", target);
+    for attr in attributes {
+        // Values files don't have to use are examples or placeholders
+        let todo = if attr.fixed { "" } else { "  # TODO" };
+        text.push_str(&format!(
+            "    {}: {},{}\n",
+            python_string(&attr.name),
+            python_value(attr),
+            todo
+        ));
+    }
+    text.push_str("})\n");
+    text
+}
+
+/// Writes a Python script that uses netCDF4 to create the file the template describes.
+/// Placeholder values are marked with TODO comments.
+pub fn make_python_script(template: &Template, requirement_info: bool) -> String {
+    let mut script = String::new();
+    script.push_str("#!/usr/bin/env python3\n");
+    script.push_str(&format!(
+        "\"\"\"Create {} following the NCAS-GENERAL standard.\n\n",
+        python_string(&template.filename)
+    ));
+    script
+        .push_str("Values marked TODO are examples or placeholders that need replacing.\n\"\"\"\n");
+    script.push_str("import netCDF4\nimport numpy as np\n\n");
+    script.push_str(&format!(
+        "ds = netCDF4.Dataset({}, \"w\", format=\"NETCDF4\")\n\n",
+        python_string(&template.filename)
+    ));
+
+    script.push_str("# Dimensions\n");
+    for dimension in &template.dimensions {
+        let dimension_text = match dimension.length {
+            Some(length) => format!(
+                "ds.createDimension({}, {})",
+                python_string(&dimension.name),
+                length
+            ),
+            None => format!(
+                "ds.createDimension({}, None)  # TODO: set the length, or leave unlimited",
+                python_string(&dimension.name)
+            ),
+        };
+        script.push_str(&with_requirement(
+            dimension_text,
+            &dimension.requirement,
+            requirement_info,
+        ));
+    }
+
+    script.push_str("\n# Variables\n");
+    for variable in &template.variables {
+        let dimensions: Vec<String> = variable
+            .dimensions
+            .iter()
+            .map(|dimension| format!("{}, ", python_string(dimension)))
+            .collect();
+        // netCDF4 won't let _FillValue be set after the variable is created
+        let fill_value = variable
+            .attributes
+            .iter()
+            .find(|attr| attr.name == "_FillValue")
+            .map(|attr| format!(", fill_value={}", python_value(attr)))
+            .unwrap_or_default();
+        let variable_text = format!(
+            "var = ds.createVariable({}, {}, ({}){})",
+            python_string(&variable.name),
+            variable_datatype(variable.data_type),
+            dimensions.concat().trim_end_matches(' '),
+            fill_value
+        );
+        script.push_str(&with_requirement(
+            variable_text,
+            &variable.requirement,
+            requirement_info,
+        ));
+        let attributes: Vec<&TemplateAttribute> = variable
+            .attributes
+            .iter()
+            .filter(|attr| attr.name != "_FillValue")
+            .collect();
+        script.push_str(&attributes_text("var", &attributes));
+    }
+
+    script.push_str("\n# Global attributes\n");
+    let global_attributes: Vec<&TemplateAttribute> = template.global_attributes.iter().collect();
+    script.push_str(&attributes_text("ds", &global_attributes));
+    script.push_str("\nds.close()\n");
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncas_netcdf::test_fixtures;
+    use indexmap::IndexMap;
+
+    #[test]
+    fn strings_are_quoted_for_python() {
+        assert_eq!(
+            python_string("a \"b\"\\c\n\t\x07é"),
+            "\"a \\\"b\\\"\\\\c\\n\\t\\x07é\""
+        );
+    }
+
+    #[tokio::test]
+    async fn script_creates_the_template() {
+        let cdl_data = test_fixtures::cdl_data(test_fixtures::attribute_values()).await;
+        let script = make_python_script(&cdl_data.template, true);
+        let expected_lines = [
+            "ds = netCDF4.Dataset(\"ncas-ceilometer-3_cao_20240101_aerosol-backscatter_v1.0.nc\", \
+             \"w\", format=\"NETCDF4\")",
+            "ds.createDimension(\"time\", None)  # TODO: set the length, or leave unlimited  \
+             # Required",
+            "ds.createDimension(\"latitude\", 1)  # Required",
+            "var = ds.createVariable(\"latitude\", \"float32\", ())  # Required",
+            "var = ds.createVariable(\"altitude\", \"float32\", (\"time\",), \
+             fill_value=np.float32(-1e20))  # Optional",
+            "    \"valid_min\": np.float32(0.5),",
+            "var = ds.createVariable(\"qc_flag\", \"int8\", (\"time\", \"altitude\",), \
+             fill_value=np.int8(0))  # Optional",
+            "    \"flag_values\": np.array([0, 1, 2], dtype=\"int8\"),",
+            "    \"title\": \"Ceilometer backscatter at CAO\",",
+            "ds.close()",
+        ];
+        for line in expected_lines {
+            assert!(script.lines().any(|l| l == line), "{}\n{}", line, script);
+        }
+        // _FillValue is given when the variable is created, not as an attribute
+        assert!(!script.contains("\"_FillValue\""));
+    }
+
+    #[tokio::test]
+    async fn values_that_are_not_fixed_are_marked_todo() {
+        let cdl_data = test_fixtures::cdl_data(IndexMap::new()).await;
+        let script = make_python_script(&cdl_data.template, false);
+        assert!(script.contains("    \"Conventions\": \"CF-1.6, NCAS-GENERAL-2.0\",\n"));
+        assert!(script.contains("    \"creator_name\": \"EXAMPLE: Jane Doe\",  # TODO\n"));
+        assert!(script.contains("    \"laser_wavelength\": \"EXAMPLE: 905 nm\",  # TODO\n"));
+        assert!(!script.contains("# Required"));
+    }
+
+    #[tokio::test]
+    async fn file_name_is_escaped_in_the_docstring() {
+        let mut cdl_data = test_fixtures::cdl_data(test_fixtures::attribute_values()).await;
+        cdl_data.template.filename = "x\"\"\"\nimport os\n\"\"\".nc".to_string();
+        let script = make_python_script(&cdl_data.template, false);
+        assert!(script.starts_with(
+            "#!/usr/bin/env python3\n\"\"\"Create \"x\\\"\\\"\\\"\\nimport os\\n\\\"\\\"\\\".nc\" \
+             following the NCAS-GENERAL standard.\n"
+        ));
+        assert!(!script.lines().any(|line| line == "import os"));
+    }
+}
@@ -0,0 +1,461 @@
+use super::netcdf_components::GlobalAttribute;
+use super::template::{DataType, parse_number};
+use indexmap::IndexMap;
+use regex::Regex;
+use serde::{Serialize, Serializer};
+
+// Patterns the CVs refer to by name with `regex-rule:<name>`
+const NAMED_REGEXES: [(&str, &str); 6] = [
+    ("datetime", r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?"),
+    (
+        "datetime-zulu",
+        r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?Z",
+    ),
+    ("valid-email", r"[^@\s]+@[^@\s]+\.[^@\s]+"),
+    ("valid-url", r"https?://[^\s]+\.[^\s]+"),
+    ("integer", r"-?\d+"),
+    ("number", r"-?(\d+\.?\d*|\.\d+)([eE][-+]?\d+)?"),
+];
+
+/// A regex the whole of a value has to match, as the CVs' regexes are written to be used.
+#[derive(Debug, Clone)]
+pub struct WholeValueRegex {
+    pattern: String,
+    regex: Regex,
+}
+
+impl WholeValueRegex {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(WholeValueRegex {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&format!("^(?:{})$", pattern))?,
+        })
+    }
+
+    /// The pattern as the CVs give it.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+}
+
+impl Serialize for WholeValueRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.pattern)
+    }
+}
+
+/// A rule from the "Compliance checking rules" column of the CVs.
+#[derive(Debug)]
+pub enum Rule {
+    /// `regex:<pattern>` or `regex-rule:<name>`. The whole value must match.
+    Regex(WholeValueRegex),
+    /// `rule-func:string-of-length:<n>` or `<n>+` for at least that many characters.
+    StringOfLength {
+        length: usize,
+        or_more: bool,
+    },
+    /// `rule-func:match-one-of:a|b|c`
+    MatchOneOf(Vec<String>),
+    /// `rule-func:match-one-or-more-of:a|b|c`, with the value a comma separated list.
+    MatchOneOrMoreOf(Vec<String>),
+    /// `rule-func:date-string`, an ISO 8601 date or date and time.
+    DateString,
+    /// `type-rule:<type>`, a value that can be read as that type.
+    Type(DataType),
+    ValidYear,
+    ValidMonth,
+    ValidDay,
+    /// A rule that can't be checked here, and why.
+    Unsupported(String),
+}
+
+/// The result of checking a value against a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    NotChecked,
+}
+
+fn whole_value_regex(pattern: &str) -> Rule {
+    match WholeValueRegex::new(pattern) {
+        Ok(regex) => Rule::Regex(regex),
+        Err(err) => Rule::Unsupported(format!("the regex does not compile: {}", err)),
+    }
+}
+
+fn options(list: &str) -> Vec<String> {
+    list.split('|')
+        .map(|option| option.trim().to_string())
+        .collect()
+}
+
+fn is_leap_year(year: u32) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+fn digits(value: &str, count: usize) -> Option<u32> {
+    if value.len() == count && value.bytes().all(|b| b.is_ascii_digit()) {
+        value.parse().ok()
+    } else {
+        None
+    }
+}
+
+// YYYY-MM-DD, optionally followed by Thh:mm:ss with fractional seconds and a Z
+fn is_date_string(value: &str) -> bool {
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+    let mut parts = date.split('-');
+    let (Some(year), Some(month), Some(day), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let (Some(year), Some(month), Some(day)) = (digits(year, 4), digits(month, 2), digits(day, 2))
+    else {
+        return false;
+    };
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => return false,
+    };
+    if day == 0 || day > days_in_month {
+        return false;
+    }
+    let Some(time) = time else {
+        return true;
+    };
+    let time = time.strip_suffix('Z').unwrap_or(time);
+    let (time, fraction) = match time.split_once('.') {
+        Some((time, fraction)) => (time, Some(fraction)),
+        None => (time, None),
+    };
+    if fraction.is_some_and(|f| f.is_empty() || !f.bytes().all(|b| b.is_ascii_digit())) {
+        return false;
+    }
+    let mut parts = time.split(':');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(hour), Some(minute), Some(second), None) => {
+            digits(hour, 2).is_some_and(|h| h < 24)
+                && digits(minute, 2).is_some_and(|m| m < 60)
+                // Allow for leap seconds
+                && digits(second, 2).is_some_and(|s| s <= 60)
+        }
+        _ => false,
+    }
+}
+
+impl Rule {
+    pub fn parse(rule: &str) -> Rule {
+        let rule = rule.trim();
+        if let Some(pattern) = rule.strip_prefix("regex:") {
+            return whole_value_regex(pattern);
+        }
+        if let Some(name) = rule.strip_prefix("regex-rule:") {
+            return match NAMED_REGEXES
+                .iter()
+                .find(|(known, _)| *known == name.trim())
+            {
+                Some((_, pattern)) => whole_value_regex(pattern),
+                None => Rule::Unsupported(format!("unknown regex rule {}", name)),
+            };
+        }
+        if let Some(type_name) = rule.strip_prefix("type-rule:") {
+            return match DataType::from_cdl_name(type_name.trim()) {
+                Some(data_type) => Rule::Type(data_type),
+                None => Rule::Unsupported(format!("unknown type {}", type_name)),
+            };
+        }
+        match rule
+            .strip_prefix("rule-func:")
+            .map(|func| func.split_once(':'))
+        {
+            Some(Some(("string-of-length", length))) => {
+                let (length, or_more) = match length.trim().strip_suffix('+') {
+                    Some(length) => (length, true),
+                    None => (length.trim(), false),
+                };
+                match length.parse() {
+                    Ok(length) => Rule::StringOfLength { length, or_more },
+                    Err(_) => Rule::Unsupported(format!("invalid length {}", length)),
+                }
+            }
+            Some(Some(("match-one-of", list))) => Rule::MatchOneOf(options(list)),
+            Some(Some(("match-one-or-more-of", list))) => Rule::MatchOneOrMoreOf(options(list)),
+            Some(None) if rule == "rule-func:date-string" => Rule::DateString,
+            _ => match rule {
+                "ValidYear" => Rule::ValidYear,
+                "ValidMonth" => Rule::ValidMonth,
+                "ValidDay" => Rule::ValidDay,
+                _ => Rule::Unsupported(format!("unknown rule {}", rule)),
+            },
+        }
+    }
+
+    /// Checks a value, giving the reason it fails or can't be checked.
+    pub fn check(&self, value: &str) -> (CheckStatus, Option<String>) {
+        let trimmed = value.trim();
+        let failure = match self {
+            Rule::Regex(regex) => {
+                (!regex.is_match(value)).then(|| format!("does not match {}", regex.pattern()))
+            }
+            Rule::StringOfLength { length, or_more } => {
+                let count = trimmed.chars().count();
+                match or_more {
+                    true => (count < *length)
+                        .then(|| format!("must be at least {} characters long", length)),
+                    false => (count != *length)
+                        .then(|| format!("must be exactly {} characters long", length)),
+                }
+            }
+            Rule::MatchOneOf(options) => (!options.iter().any(|option| option == trimmed))
+                .then(|| format!("must be one of {}", options.join(", "))),
+            Rule::MatchOneOrMoreOf(options) => (!trimmed
+                .split(',')
+                .all(|value| options.iter().any(|option| option == value.trim())))
+            .then(|| format!("must be a comma separated list of {}", options.join(", "))),
+            Rule::DateString => (!is_date_string(trimmed))
+                .then(|| "must be a date such as 2016-07-06T00:00:00".to_string()),
+            Rule::Type(data_type) => parse_number(trimmed, *data_type)
+                .is_none()
+                .then(|| format!("must be a {}", data_type.name())),
+            Rule::ValidYear => digits(trimmed, 4)
+                .is_none()
+                .then(|| "must be a four digit year".to_string()),
+            Rule::ValidMonth => (!digits(trimmed, 2).is_some_and(|m| (1..=12).contains(&m)))
+                .then(|| "must be a month from 01 to 12".to_string()),
+            Rule::ValidDay => (!digits(trimmed, 2).is_some_and(|d| (1..=31).contains(&d)))
+                .then(|| "must be a day from 01 to 31".to_string()),
+            Rule::Unsupported(reason) => {
+                return (
+                    CheckStatus::NotChecked,
+                    Some(format!("Not checked: {}", reason)),
+                );
+            }
+        };
+        match failure {
+            Some(reason) => (CheckStatus::Fail, Some(reason)),
+            None => (CheckStatus::Pass, None),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttributeCheck {
+    pub name: String,
+    pub value: String,
+    pub status: CheckStatus,
+    /// The rule that decided the status, as the CVs write it.
+    pub rule: Option<String>,
+    pub message: Option<String>,
+}
+
+/// Per attribute results of checking values against the CVs' rules.
+#[derive(Debug, Serialize)]
+pub struct AttributeReport {
+    /// True when no value failed a rule and every attribute is in the CVs.
+    pub passed: bool,
+    pub results: Vec<AttributeCheck>,
+    /// Attributes that were given values but that the CVs don't define.
+    pub unknown_attributes: Vec<String>,
+}
+
+impl AttributeReport {
+    /// Describes each value that failed and each attribute that isn't in the CVs.
+    pub fn problems(&self) -> Vec<String> {
+        let failures = self
+            .results
+            .iter()
+            .filter(|result| result.status == CheckStatus::Fail)
+            .map(|result| {
+                format!(
+                    "{} {}",
+                    result.name,
+                    result.message.as_deref().unwrap_or("is invalid")
+                )
+            });
+        let unknown = self.unknown_attributes.iter().map(|name| {
+            format!(
+                "{} is not a global attribute of this data product, so its value was not used",
+                name
+            )
+        });
+        failures.chain(unknown).collect()
+    }
+}
+
+/// Checks a value for a global attribute against its fixed value, if the CVs give one,
+/// then its regex check and compliance checking rule.
+pub fn check_global_attribute(attr: &GlobalAttribute, value: &str) -> AttributeCheck {
+    let regex_rule = |regex: &WholeValueRegex| Some(format!("regex check: {}", regex.pattern()));
+    let (status, rule, message) = if !attr.value.is_empty() && attr.value != value {
+        (
+            CheckStatus::Fail,
+            Some("fixed value".to_string()),
+            Some(format!("must be {:?}", attr.value)),
+        )
+    } else if let Some(regex) = attr.regex_check.as_ref().filter(|r| !r.is_match(value)) {
+        (
+            CheckStatus::Fail,
+            regex_rule(regex),
+            Some(format!("does not match {}", regex.pattern())),
+        )
+    } else if let (Some(regex), true) = (&attr.regex_check, attr.compliance.trim().is_empty()) {
+        (CheckStatus::Pass, regex_rule(regex), None)
+    } else if attr.compliance.trim().is_empty() {
+        (
+            CheckStatus::NotChecked,
+            None,
+            Some("The CVs have no rule for this attribute".to_string()),
+        )
+    } else {
+        let (status, message) = Rule::parse(&attr.compliance).check(value);
+        (status, Some(attr.compliance.trim().to_string()), message)
+    };
+    AttributeCheck {
+        name: attr.name.clone(),
+        value: value.to_string(),
+        status,
+        rule,
+        message,
+    }
+}
+
+/// Checks values given for global attributes, in the order the CVs list the attributes.
+pub fn check_global_attributes(
+    attributes: &[&GlobalAttribute],
+    values: &IndexMap<String, String>,
+) -> AttributeReport {
+    let results: Vec<AttributeCheck> = attributes
+        .iter()
+        .filter_map(|attr| Some(check_global_attribute(attr, values.get(&attr.name)?)))
+        .collect();
+    let unknown_attributes: Vec<String> = values
+        .keys()
+        .filter(|name| !attributes.iter().any(|attr| &attr.name == *name))
+        .cloned()
+        .collect();
+    AttributeReport {
+        passed: unknown_attributes.is_empty()
+            && results
+                .iter()
+                .all(|result| result.status != CheckStatus::Fail),
+        results,
+        unknown_attributes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_check_values() {
+        use CheckStatus::{Fail, NotChecked, Pass};
+        let cases = [
+            ("regex:\\d+ nm", "905 nm", Pass),
+            // The whole value has to match
+            ("regex:\\d+ nm", "about 905 nm", Fail),
+            ("regex:(", "anything", NotChecked),
+            ("regex-rule:valid-email", "jane.doe@example.ac.uk", Pass),
+            ("regex-rule:valid-email", "jane.doe", Fail),
+            ("regex-rule:datetime-zulu", "2016-07-06T00:00:00Z", Pass),
+            ("regex-rule:datetime-zulu", "2016-07-06T00:00:00", Fail),
+            ("regex-rule:no-such-rule", "anything", NotChecked),
+            ("rule-func:string-of-length:3+", "Jane", Pass),
+            ("rule-func:string-of-length:3+", " J ", Fail),
+            ("rule-func:string-of-length:4", "1.0a", Pass),
+            ("rule-func:string-of-length:4", "1.0", Fail),
+            ("rule-func:string-of-length:x", "anything", NotChecked),
+            ("rule-func:match-one-of:land|sea|air", "sea", Pass),
+            ("rule-func:match-one-of:land|sea|air", "lake", Fail),
+            ("rule-func:match-one-or-more-of:a|b|c", "a, c", Pass),
+            ("rule-func:match-one-or-more-of:a|b|c", "a, d", Fail),
+            ("rule-func:date-string", "2016-07-06", Pass),
+            ("rule-func:date-string", "2016-07-06T23:59:60.5Z", Pass),
+            ("rule-func:date-string", "2024-02-29", Pass),
+            ("rule-func:date-string", "2023-02-29", Fail),
+            ("rule-func:date-string", "2016-07-06T24:00:00", Fail),
+            ("rule-func:date-string", "06/07/2016", Fail),
+            ("type-rule:float", "1.5e3", Pass),
+            ("type-rule:int", "1.5", Fail),
+            ("type-rule:complex", "1", NotChecked),
+            ("ValidYear", "2016", Pass),
+            ("ValidYear", "16", Fail),
+            ("ValidMonth", "12", Pass),
+            ("ValidMonth", "13", Fail),
+            ("ValidDay", "31", Pass),
+            ("ValidDay", "00", Fail),
+            ("rule-func:unknown-function:x", "anything", NotChecked),
+        ];
+        for (rule, value, expected) in cases {
+            let (status, message) = Rule::parse(rule).check(value);
+            assert_eq!(status, expected, "{} with {:?}", rule, value);
+            assert_eq!(
+                message.is_some(),
+                status != Pass,
+                "{} with {:?}",
+                rule,
+                value
+            );
+        }
+    }
+
+    fn attribute(name: &str, value: &str, compliance: &str) -> GlobalAttribute {
+        GlobalAttribute {
+            name: name.to_string(),
+            value: value.to_string(),
+            example: String::new(),
+            compliance: compliance.to_string(),
+            regex_check: None,
+        }
+    }
+
+    #[test]
+    fn fixed_values_and_regex_checks_come_before_rules() {
+        let conventions = attribute("Conventions", "CF-1.6", "regex:CF-.*");
+        let check = check_global_attribute(&conventions, "CF-1.8");
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert_eq!(check.rule.as_deref(), Some("fixed value"));
+
+        let mut start = attribute("time_coverage_start", "", "rule-func:date-string");
+        start.regex_check = WholeValueRegex::new("\\d{4}-\\d{2}-\\d{2}T\\d{2}:\\d{2}:\\d{2}").ok();
+        let check = check_global_attribute(&start, "2016-07-06");
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.rule.unwrap().starts_with("regex check"));
+        let check = check_global_attribute(&start, "2016-07-06T00:00:00");
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert_eq!(check.rule.as_deref(), Some("rule-func:date-string"));
+    }
+
+    #[test]
+    fn report_lists_failures_and_unknown_attributes() {
+        let creator = attribute("creator_name", "", "rule-func:string-of-length:3+");
+        let title = attribute("title", "", "");
+        let values: IndexMap<String, String> = [
+            ("creator_name", "JD"),
+            ("title", "Anything"),
+            ("platform", "cao"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let report = check_global_attributes(&[&creator, &title], &values);
+        assert!(!report.passed);
+        let statuses: Vec<CheckStatus> = report.results.iter().map(|r| r.status).collect();
+        assert_eq!(statuses, [CheckStatus::Fail, CheckStatus::NotChecked]);
+        assert_eq!(report.unknown_attributes, ["platform"]);
+        assert_eq!(report.problems().len(), 2);
+    }
+}
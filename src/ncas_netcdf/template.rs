@@ -0,0 +1,527 @@
+use super::common::Common;
+use super::data_products::DataProduct;
+use super::deployments::Deployment;
+use super::error::NcasCdlError;
+use super::instruments::InstRecord;
+use super::netcdf_components::{GlobalAttribute, Variable};
+use super::rules::WholeValueRegex;
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::fmt;
+
+/// Everything a file following the standard should contain, merged from the common,
+/// deployment mode and data product CVs. The CDL and other outputs are rendered from this.
+#[derive(Debug, Serialize)]
+pub struct Template {
+    pub filename: String,
+    pub dimensions: Vec<TemplateDimension>,
+    pub variables: Vec<TemplateVariable>,
+    pub global_attributes: Vec<TemplateAttribute>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "level", content = "condition", rename_all = "snake_case")]
+pub enum RequirementLevel {
+    Required,
+    RequiredIf(String),
+    Optional,
+}
+
+impl fmt::Display for RequirementLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequirementLevel::Required => write!(f, "Required"),
+            RequirementLevel::RequiredIf(reason) => write!(f, "Required if {}", reason),
+            RequirementLevel::Optional => write!(f, "Optional"),
+        }
+    }
+}
+
+/// The NetCDF types, named as they are in CDL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    Byte,
+    Ubyte,
+    Short,
+    Ushort,
+    Int,
+    Uint,
+    Int64,
+    Uint64,
+    Float,
+    Double,
+    Char,
+    String,
+}
+
+impl DataType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataType::Byte => "byte",
+            DataType::Ubyte => "ubyte",
+            DataType::Short => "short",
+            DataType::Ushort => "ushort",
+            DataType::Int => "int",
+            DataType::Uint => "uint",
+            DataType::Int64 => "int64",
+            DataType::Uint64 => "uint64",
+            DataType::Float => "float",
+            DataType::Double => "double",
+            DataType::Char => "char",
+            DataType::String => "string",
+        }
+    }
+
+    /// Reads a type keyword from CDL, including the older names ncgen still accepts.
+    pub fn from_cdl_name(name: &str) -> Option<Self> {
+        let data_type = match name {
+            "byte" => DataType::Byte,
+            "ubyte" => DataType::Ubyte,
+            "short" => DataType::Short,
+            "ushort" => DataType::Ushort,
+            "int" | "long" => DataType::Int,
+            "uint" => DataType::Uint,
+            "int64" => DataType::Int64,
+            "uint64" => DataType::Uint64,
+            "float" | "real" => DataType::Float,
+            "double" => DataType::Double,
+            "char" => DataType::Char,
+            "string" => DataType::String,
+            _ => return None,
+        };
+        Some(data_type)
+    }
+
+    // Map a type from the CVs, which use both the CDL and numpy style names
+    fn from_cv(variable: &Variable) -> Result<Self, NcasCdlError> {
+        let data_type = variable
+            .attributes
+            .get("type")
+            .map(|t| t.trim())
+            .unwrap_or_default();
+        let data_type = match data_type {
+            "int8" | "byte" => DataType::Byte,
+            "uint8" | "ubyte" => DataType::Ubyte,
+            "int16" | "short" => DataType::Short,
+            "uint16" | "ushort" => DataType::Ushort,
+            "int32" | "int" => DataType::Int,
+            "uint32" | "uint" => DataType::Uint,
+            "int64" => DataType::Int64,
+            "uint64" => DataType::Uint64,
+            "float32" | "float" => DataType::Float,
+            "float64" | "double" => DataType::Double,
+            "char" => DataType::Char,
+            "string" => DataType::String,
+            _ => {
+                return Err(NcasCdlError::UnknownDataType {
+                    variable: variable.name.clone(),
+                    data_type: data_type.to_string(),
+                    file: variable.source.clone(),
+                });
+            }
+        };
+        Ok(data_type)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Number {
+    Signed(i64),
+    Unsigned(u64),
+    Float(f64),
+}
+
+// Floats are written with Debug so whole numbers keep a decimal point, e.g. "2.0"
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Signed(v) => write!(f, "{}", v),
+            Number::Unsigned(v) => write!(f, "{}", v),
+            Number::Float(v) => write!(f, "{:?}", v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AttributeValue {
+    Text(String),
+    Numbers(Vec<Number>),
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplateDimension {
+    pub name: String,
+    /// None when the length depends on the data, e.g. for time.
+    pub length: Option<u32>,
+    pub requirement: RequirementLevel,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplateVariable {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub data_type: DataType,
+    pub dimensions: Vec<String>,
+    pub requirement: RequirementLevel,
+    pub attributes: Vec<TemplateAttribute>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplateAttribute {
+    pub name: String,
+    /// Char for text, otherwise the type of the numbers in the value.
+    #[serde(rename = "type")]
+    pub data_type: DataType,
+    pub value: AttributeValue,
+    /// Whether files must use exactly this value, rather than it being an example or a
+    /// placeholder for something that depends on the data.
+    pub fixed: bool,
+    pub requirement: RequirementLevel,
+    /// The CVs' compliance checking rule for the value, e.g. `regex:\d+ nm`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// A pattern the whole value must match, from the CV's regex check column.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regex_check: Option<WholeValueRegex>,
+}
+
+// Attributes whose values have the same type as the variable they describe
+const VARIABLE_TYPED_ATTRIBUTES: [&str; 8] = [
+    "valid_min",
+    "valid_max",
+    "valid_range",
+    "actual_range",
+    "flag_values",
+    "flag_masks",
+    "missing_value",
+    "_FillValue",
+];
+
+/// Parses a single number as the given type. The CVs sometimes include CDL type suffixes
+/// such as "2b", so trailing letters are dropped from integers before parsing.
+pub fn parse_number(value: &str, data_type: DataType) -> Option<Number> {
+    let integer = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    match data_type {
+        DataType::Byte => integer.parse::<i8>().ok().map(|v| Number::Signed(v.into())),
+        DataType::Ubyte => integer
+            .parse::<u8>()
+            .ok()
+            .map(|v| Number::Unsigned(v.into())),
+        DataType::Short => integer
+            .parse::<i16>()
+            .ok()
+            .map(|v| Number::Signed(v.into())),
+        DataType::Ushort => integer
+            .parse::<u16>()
+            .ok()
+            .map(|v| Number::Unsigned(v.into())),
+        DataType::Int => integer
+            .parse::<i32>()
+            .ok()
+            .map(|v| Number::Signed(v.into())),
+        DataType::Uint => integer
+            .parse::<u32>()
+            .ok()
+            .map(|v| Number::Unsigned(v.into())),
+        DataType::Int64 => integer.parse::<i64>().ok().map(Number::Signed),
+        DataType::Uint64 => integer.parse::<u64>().ok().map(Number::Unsigned),
+        DataType::Float => {
+            // Check it fits in a float, but keep the value as written rather than the
+            // nearest float so it isn't shown as e.g. 0.10000000149011612
+            let float = value.trim_end_matches(['f', 'F']);
+            float
+                .parse::<f32>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .and_then(|_| float.parse::<f64>().ok())
+                .map(Number::Float)
+        }
+        DataType::Double => value
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .map(Number::Float),
+        DataType::Char | DataType::String => None,
+    }
+}
+
+// Parse a comma or space separated list of numbers, or None if any of them isn't one
+fn parse_numbers(value: &str, data_type: DataType) -> Option<Vec<Number>> {
+    let numbers: Option<Vec<Number>> = value
+        .split([',', ' ', '\t'])
+        .filter(|v| !v.is_empty())
+        .map(|v| parse_number(v, data_type))
+        .collect();
+    numbers.filter(|numbers| !numbers.is_empty())
+}
+
+// Fill value to use when the CV doesn't give one that fits the variable's type
+fn default_fill_value(data_type: DataType) -> Option<Number> {
+    match data_type {
+        DataType::Byte => Some(Number::Signed(-127)),
+        DataType::Ubyte => Some(Number::Unsigned(255)),
+        DataType::Short => Some(Number::Signed(-32767)),
+        DataType::Ushort => Some(Number::Unsigned(65535)),
+        DataType::Int => Some(Number::Signed(-2147483647)),
+        DataType::Uint => Some(Number::Unsigned(4294967295)),
+        DataType::Int64 => Some(Number::Signed(-9223372036854775806)),
+        DataType::Uint64 => Some(Number::Unsigned(18446744073709551614)),
+        DataType::Float | DataType::Double => Some(Number::Float(-1.0e20)),
+        DataType::Char | DataType::String => None,
+    }
+}
+
+/// The numeric type a variable attribute should have, e.g. the flag_values of a byte
+/// variable are bytes, or None for text attributes.
+pub fn numeric_attribute_type(attr_name: &str, vartype: DataType) -> Option<DataType> {
+    match attr_name {
+        // The type of these gives the type of the unpacked data, so only follow the
+        // variable's type when it is already floating point
+        "scale_factor" | "add_offset" if vartype == DataType::Float => Some(DataType::Float),
+        "scale_factor" | "add_offset" => Some(DataType::Double),
+        name if VARIABLE_TYPED_ATTRIBUTES.contains(&name) => Some(vartype),
+        _ => None,
+    }
+}
+
+// Values like "<derived from file>" that stand in for something only known from the data
+fn is_placeholder(value: &str) -> bool {
+    value.starts_with('<') && value.ends_with('>')
+}
+
+// Give a variable attribute the type it should have. Text attributes, and values that
+// aren't numbers such as placeholders, are kept as text.
+fn variable_attribute(
+    attr_name: &str,
+    attr_value: &str,
+    vartype: DataType,
+    requirement: RequirementLevel,
+    rule: Option<&String>,
+) -> TemplateAttribute {
+    let value_type = numeric_attribute_type(attr_name, vartype);
+    let numbers = if attr_name == "_FillValue" {
+        parse_numbers(attr_value, vartype)
+            .filter(|numbers| numbers.len() == 1)
+            .or_else(|| default_fill_value(vartype).map(|v| vec![v]))
+    } else {
+        value_type.and_then(|value_type| parse_numbers(attr_value, value_type))
+    };
+    let (data_type, value) = match (value_type, numbers) {
+        (Some(value_type), Some(numbers)) => (value_type, AttributeValue::Numbers(numbers)),
+        _ => (DataType::Char, AttributeValue::Text(attr_value.to_string())),
+    };
+    TemplateAttribute {
+        name: attr_name.to_string(),
+        data_type,
+        value,
+        fixed: !is_placeholder(attr_value.trim()),
+        requirement,
+        rule: rule.cloned(),
+        regex_check: None,
+    }
+}
+
+fn template_variable(
+    variable: &Variable,
+    requirement_level_variable: RequirementLevel,
+    requirement_level_varattr: RequirementLevel,
+) -> Result<TemplateVariable, NcasCdlError> {
+    let data_type = DataType::from_cv(variable)?;
+    let dimensions = variable
+        .attributes
+        .get("dimension")
+        .map(|dims| {
+            dims.split(',')
+                .map(|dim| dim.trim().to_string())
+                .filter(|dim| !dim.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    let attributes = variable
+        .attributes
+        .iter()
+        // The type and dimensions are part of the variable's declaration
+        .filter(|(attr_name, _)| *attr_name != "type" && *attr_name != "dimension")
+        .map(|(attr_name, attr_value)| {
+            variable_attribute(
+                attr_name,
+                attr_value,
+                data_type,
+                requirement_level_varattr.clone(),
+                variable.rules.get(attr_name),
+            )
+        })
+        .collect();
+    Ok(TemplateVariable {
+        name: variable.name.clone(),
+        data_type,
+        dimensions,
+        requirement: requirement_level_variable,
+        attributes,
+    })
+}
+
+// A value given for the attribute is used in place of an example, but not in place of
+// a value the CV or the instrument's record fixes
+fn global_attribute(
+    attr: &GlobalAttribute,
+    instrument_record: &InstRecord,
+    given_value: Option<&String>,
+) -> TemplateAttribute {
+    let value = if attr.name == "instrument_manufacturer" {
+        instrument_record.manufacturer.clone()
+    } else if attr.name == "instrument_model" {
+        instrument_record.model_no.clone()
+    } else if attr.name == "instrument_serial_number" {
+        instrument_record.serial_number.clone()
+    } else if attr.name == "source" {
+        instrument_record.descriptor.clone()
+    } else {
+        Some(if attr.value.is_empty() {
+            format!("EXAMPLE: {}", attr.example.clone())
+        } else {
+            attr.value.clone()
+        })
+    };
+    // Values from the CV or the instrument's record are the ones files must have
+    let fixed = value.as_ref().is_some_and(|v| !v.starts_with("EXAMPLE: "));
+    let (fixed, value) = match (value, given_value) {
+        (Some(v), _) if fixed => (true, v),
+        (_, Some(given)) => (true, given.clone()),
+        (Some(v), None) => (false, v),
+        (None, None) => (false, "EXAMPLE".to_string()),
+    };
+    TemplateAttribute {
+        name: attr.name.clone(),
+        data_type: DataType::Char,
+        value: AttributeValue::Text(value),
+        fixed,
+        requirement: RequirementLevel::Required,
+        rule: Some(attr.compliance.trim().to_string()).filter(|rule| !rule.is_empty()),
+        regex_check: attr.regex_check.clone(),
+    }
+}
+
+pub fn make_template(
+    filename: String,
+    common: &Common,
+    deployment: &Deployment,
+    data_product: &DataProduct,
+    instrument_record: &InstRecord,
+    attribute_values: &IndexMap<String, String>,
+) -> Result<Template, NcasCdlError> {
+    let dimensions = deployment
+        .dimensions
+        .iter()
+        .chain(&data_product.dimensions)
+        .map(|dimension| TemplateDimension {
+            name: dimension.name.clone(),
+            length: dimension.length,
+            requirement: RequirementLevel::Required,
+        })
+        .collect();
+    let mut variables = Vec::new();
+    for variable in &deployment.variables {
+        variables.push(template_variable(
+            variable,
+            RequirementLevel::Required,
+            RequirementLevel::Required,
+        )?);
+    }
+    for variable in &data_product.variables {
+        variables.push(template_variable(
+            variable,
+            RequirementLevel::Optional,
+            RequirementLevel::RequiredIf("the variable is present".to_string()),
+        )?);
+    }
+    let global_attributes = common
+        .global_attributes
+        .iter()
+        .chain(&data_product.global_attributes)
+        .map(|attr| global_attribute(attr, instrument_record, attribute_values.get(&attr.name)))
+        .collect();
+    Ok(Template {
+        filename,
+        dimensions,
+        variables,
+        global_attributes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(data_type: Option<&str>) -> Variable {
+        let mut attributes = IndexMap::new();
+        if let Some(data_type) = data_type {
+            attributes.insert("type".to_string(), data_type.to_string());
+        }
+        Variable {
+            name: "backscatter".to_string(),
+            attributes,
+            rules: IndexMap::new(),
+            source: "product-definitions/tsv/aerosol-backscatter/variables-specific.tsv"
+                .to_string(),
+        }
+    }
+
+    #[test]
+    fn fill_values_are_the_netcdf_defaults() {
+        let cases = [
+            (DataType::Byte, Some(Number::Signed(-127))),
+            (DataType::Ubyte, Some(Number::Unsigned(255))),
+            (DataType::Short, Some(Number::Signed(-32767))),
+            (DataType::Ushort, Some(Number::Unsigned(65535))),
+            (DataType::Int, Some(Number::Signed(-2147483647))),
+            (DataType::Uint, Some(Number::Unsigned(4294967295))),
+            (DataType::Int64, Some(Number::Signed(-9223372036854775806))),
+            (
+                DataType::Uint64,
+                Some(Number::Unsigned(18446744073709551614)),
+            ),
+            (DataType::Float, Some(Number::Float(-1.0e20))),
+            (DataType::Double, Some(Number::Float(-1.0e20))),
+            (DataType::Char, None),
+            (DataType::String, None),
+        ];
+        for (data_type, expected) in cases {
+            assert_eq!(default_fill_value(data_type), expected, "{:?}", data_type);
+        }
+    }
+
+    #[test]
+    fn cv_types_use_cdl_or_numpy_names() {
+        assert_eq!(
+            DataType::from_cv(&variable(Some("float32"))).unwrap(),
+            DataType::Float
+        );
+        assert_eq!(
+            DataType::from_cv(&variable(Some(" byte "))).unwrap(),
+            DataType::Byte
+        );
+        assert_eq!(
+            DataType::from_cv(&variable(Some("uint64"))).unwrap(),
+            DataType::Uint64
+        );
+    }
+
+    #[test]
+    fn unknown_cv_types_are_rejected() {
+        for data_type in [Some("float128"), None] {
+            let err = DataType::from_cv(&variable(data_type)).unwrap_err();
+            let NcasCdlError::UnknownDataType { variable, file, .. } = &err else {
+                panic!("unexpected error {:?}", err);
+            };
+            assert_eq!(variable, "backscatter");
+            assert!(file.ends_with("variables-specific.tsv"));
+            let message = err.to_string();
+            assert!(message.contains("backscatter"), "{}", message);
+            assert!(message.contains("variables-specific.tsv"), "{}", message);
+        }
+    }
+}
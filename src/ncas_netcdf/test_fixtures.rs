@@ -0,0 +1,174 @@
+// Small CVs for tests, at the paths the AMF_CVs and instrument vocabulary repositories
+// use. aerosol-backscatter is the only complete data product and land the only complete
+// deployment mode; surface-met and air have some files, but not all of them.
+use super::common::RegexCache;
+use super::cv_source::{CvRepository, MemoryCvSource};
+use super::{CDLData, CdlRequest};
+use indexmap::IndexMap;
+
+pub const TAG: &str = "v2.1.0";
+
+const COMMON_GLOBAL_ATTRIBUTES: &str = "\
+Name\tDescription\tExample\tFixed Value\tCompliance checking rules\tConvention Providence\t\
+Regex check (if required) - AS & JS to populate Vocabulary
+Conventions\tConventions used\tCF-1.6, NCAS-GENERAL-2.0\tCF-1.6, NCAS-GENERAL-2.0\t\t\t
+creator_name\tName of creator\tJane Doe\t\trule-func:string-of-length:3+\tNCAS\t
+time_coverage_start\tStart time\t2016-07-06T00:00:00\t\trule-func:date-string\tACDD\t\
+\\d{4}-\\d{2}-\\d{2}T\\d{2}:\\d{2}:\\d{2}
+title\tTitle\tMy title\t\trule-func:string-of-length:10+\tACDD\t
+";
+
+const LAND_DIMENSIONS: &str = "\
+Name\tLength\tunits
+time\t<i>\t
+latitude\t1\tdegrees_north
+";
+
+const LAND_VARIABLES: &str = "\
+Variable\tAttribute\tValue\tCompliance checking rules
+time\t\t\t
+\ttype\tfloat64\t
+\tdimension\ttime\t
+\tunits\tseconds since 1970-01-01 00:00:00\t
+\tstandard_name\ttime\t
+
+latitude\t\t\t
+\ttype\tfloat32\t
+\tdimension\t\t
+\tunits\tdegrees_north\t
+";
+
+const DATA_PRODUCTS: &str = "\
+Data Product\tDescription
+aerosol-backscatter\tAttenuated aerosol backscatter profiles
+";
+
+// A data product the data-products vocabulary doesn't describe yet
+const SURFACE_MET_VARIABLES: &str = "\
+Variable\tattribute\tValue
+air_temperature
+\ttype\tfloat32
+\tdimension\ttime
+\tunits\tK
+";
+
+const PRODUCT_GLOBAL_ATTRIBUTES: &str = "\
+Name\tDescription\tExample\tFixed Value\tCompliance checking rules\tConvention Providence
+laser_wavelength\tWavelength\t905 nm\t\tregex:\\d+ nm\tNCAS
+";
+
+const PRODUCT_DIMENSIONS: &str = "\
+Name\tLength
+altitude\t<n>
+";
+
+const PRODUCT_VARIABLES: &str = "\
+Variable\tattribute\tValue
+altitude
+\ttype\tfloat32
+\tdimension\ttime
+\tunits\tm
+\tvalid_min\t0.5
+\t_FillValue\t-1.00E+20
+
+qc_flag
+\ttype\tbyte
+\tdimension\ttime, altitude
+\tflag_values\t0b, 1b, 2b
+\tflag_meanings\tnot_used|good_data|bad_data
+\t_FillValue\t0b
+
+count
+\ttype\tint32
+\tdimension\ttime
+";
+
+const INSTRUMENTS: &str = "\
+Instrument\tManufacturer\tModel No.\tSerial Number\tOld Instrument Name\tNew Instrument Name\t\
+Data Product(s)\tMobile/Fixed (loc)\tHost\tScientist\tCategory\tDescriptor\tOwner\tPID
+Ceilometer\tVaisala\tCL51\tA1\tncas-ceilometer-2\tncas-ceilometer-3\taerosol-backscatter\t\
+fixed - CAO\tNCAS\tJ Smith\tRemote sensing\tNCAS Ceilometer unit 3\tNCAS\t
+Lidar\tHalo\tXR\t55\thalo-doppler-lidar-55, ncas-doppler-lidar-1\tncas-lidar-dop-2\t\
+doppler-lidar-wind-profile\tmobile\tNCAS\tJ Smith\tRemote sensing\tNCAS Doppler Lidar 2\tNCAS\t
+Weather station\tCampbell\tCR1000\t7\t\tuol-aws-1\tsurface-met; aerosol-backscatter\t\
+fixed - Leeds\tUniversity of Leeds\tA Jones\tMeteorology\tLeeds automatic weather station\t\
+University of Leeds\t
+";
+
+pub fn cv_source() -> MemoryCvSource {
+    let mut source = MemoryCvSource::new();
+    let amf_files = [
+        ("_common/global-attributes.tsv", COMMON_GLOBAL_ATTRIBUTES),
+        ("_common/dimensions-land.tsv", LAND_DIMENSIONS),
+        ("_common/variables-land.tsv", LAND_VARIABLES),
+        // Without a dimensions file, so not a deployment mode that can be used
+        ("_common/variables-air.tsv", LAND_VARIABLES),
+        ("_vocabularies/data-products.tsv", DATA_PRODUCTS),
+        (
+            "aerosol-backscatter/global-attributes-specific.tsv",
+            PRODUCT_GLOBAL_ATTRIBUTES,
+        ),
+        (
+            "aerosol-backscatter/dimensions-specific.tsv",
+            PRODUCT_DIMENSIONS,
+        ),
+        (
+            "aerosol-backscatter/variables-specific.tsv",
+            PRODUCT_VARIABLES,
+        ),
+        ("surface-met/variables-specific.tsv", SURFACE_MET_VARIABLES),
+    ];
+    for (path, contents) in amf_files {
+        source.insert(
+            CvRepository::AmfCvs,
+            TAG,
+            &format!("product-definitions/tsv/{}", path),
+            contents,
+        );
+    }
+    source.insert(
+        CvRepository::InstrumentVocabs,
+        "refs/heads/main",
+        "product-definitions/tsv/_instrument_vocabs/ncas-instrument-name-and-descriptors.tsv",
+        INSTRUMENTS,
+    );
+    source
+}
+
+/// Values that pass the rules for every global attribute the CVs leave to the user.
+pub fn attribute_values() -> IndexMap<String, String> {
+    [
+        ("creator_name", "Jane Doe"),
+        ("time_coverage_start", "2024-01-01T00:00:00"),
+        ("title", "Ceilometer backscatter at CAO"),
+        ("laser_wavelength", "905 nm"),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect()
+}
+
+pub fn request(instrument: &str, attribute_values: IndexMap<String, String>) -> CdlRequest {
+    CdlRequest {
+        instrument_name: instrument.to_string(),
+        data_product: "aerosol-backscatter".to_string(),
+        deployment: "land".to_string(),
+        start_date: "20240101".to_string(),
+        tag: TAG.to_string(),
+        allow_unregistered_instrument: false,
+        strict: true,
+        attribute_values,
+        profile_values: IndexMap::new(),
+    }
+}
+
+/// The template for ncas-ceilometer-3, with any values given filled in.
+pub async fn cdl_data(attribute_values: IndexMap<String, String>) -> CDLData {
+    super::main(
+        &cv_source(),
+        &RegexCache::default(),
+        request("ncas-ceilometer-3", attribute_values),
+    )
+    .await
+    .unwrap()
+}